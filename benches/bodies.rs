@@ -0,0 +1,181 @@
+//! The cost of handling message bodies: memory allocated per message, which
+//! is printed before the benchmarks run, and time taken.
+//!
+//! Decoded bodies share the read buffer. Each figure is set against a
+//! baseline which copies the body, as the codec used to: decoding into a
+//! `Vec<u8>` per body, and encoding into a write buffer, which is what
+//! `Encoder::encode` still does. `ClientCodec::encode_buf` only writes the
+//! headers and chains the body after them, as connections send frames.
+//! Results are kept in `RESULTS.md`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::{Buf, Bytes, BytesMut};
+use criterion::{criterion_group, BenchmarkId, Criterion, Throughput};
+use tokio_util::codec::{Decoder, Encoder};
+
+use tokio_stomp_2::client::ClientCodec;
+use tokio_stomp_2::server::ServerCodec;
+use tokio_stomp_2::{FromServer, ToServer};
+
+/// Counts the bytes allocated, leaving the work to the system allocator
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size.saturating_sub(layout.size()), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const SIZES: [usize; 2] = [1024, 64 * 1024];
+const MESSAGES: usize = 64;
+
+fn body(size: usize) -> Bytes {
+    (0..size).map(|i| b'a' + (i % 26) as u8).collect()
+}
+
+/// `MESSAGES` MESSAGE frames with bodies of `size` bytes, as read from a socket
+fn incoming(size: usize) -> BytesMut {
+    let body = body(size);
+    let mut buffer = BytesMut::new();
+    for i in 0..MESSAGES {
+        let msg = FromServer::Message {
+            destination: "/topic/feed".into(),
+            message_id: i.to_string(),
+            subscription: "0".into(),
+            ack: None,
+            headers: vec![],
+            body: Some(body.clone()),
+        };
+        ServerCodec::new().encode(msg.into(), &mut buffer).unwrap();
+    }
+    buffer
+}
+
+/// Decode every frame in `buffer`, keeping the messages as an application would
+fn decode_all(buffer: BytesMut) -> Vec<FromServer> {
+    let mut buffer = buffer;
+    let mut codec = ClientCodec::new();
+    let mut received = Vec::with_capacity(MESSAGES);
+    while let Some(msg) = codec.decode(&mut buffer).unwrap() {
+        received.push(msg.content);
+    }
+    assert_eq!(received.len(), MESSAGES);
+    received
+}
+
+/// The baseline for `decode_all`: every body copied out of the read buffer
+fn decode_copied(buffer: BytesMut) -> Vec<Vec<u8>> {
+    decode_all(buffer)
+        .into_iter()
+        .map(|msg| match msg {
+            FromServer::Message { body, .. } => body.unwrap().to_vec(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn send(body: &Bytes) -> ToServer {
+    ToServer::send("/topic/feed", body.clone())
+}
+
+/// Send the same body `MESSAGES` times, as when publishing to several
+/// destinations, copying it into the write buffer each time. The baseline for
+/// `encode_chained`.
+fn encode_all(body: &Bytes) -> BytesMut {
+    let mut codec = ClientCodec::new();
+    let mut buffer = BytesMut::new();
+    for _ in 0..MESSAGES {
+        codec.encode(send(body).into(), &mut buffer).unwrap();
+    }
+    buffer
+}
+
+/// As `encode_all`, keeping the encoded frames queued as they would be for
+/// writing, with the body chained after the headers
+fn encode_chained(body: &Bytes) -> Vec<impl Buf> {
+    let mut codec = ClientCodec::new();
+    let mut frames = Vec::with_capacity(MESSAGES);
+    for _ in 0..MESSAGES {
+        frames.push(codec.encode_buf(send(body).into()));
+    }
+    frames
+}
+
+/// Bytes allocated per message while running `f`
+fn allocated<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let result = f();
+    let after = ALLOCATED.load(Ordering::Relaxed);
+    drop(result);
+    (after - before) / MESSAGES
+}
+
+fn report_allocations() {
+    println!("bytes allocated per message (baseline which copies the body -> now)");
+    for size in SIZES {
+        let buffer = incoming(size);
+        let copied = allocated(|| decode_copied(buffer));
+        let buffer = incoming(size);
+        let decoded = allocated(|| decode_all(buffer));
+        let body = body(size);
+        let buffered = allocated(|| encode_all(&body));
+        let chained = allocated(|| encode_chained(&body));
+        println!(
+            "{} byte bodies: decode {} -> {}, encode {} -> {}",
+            size, copied, decoded, buffered, chained
+        );
+    }
+}
+
+fn bodies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bodies");
+    for size in SIZES {
+        group.throughput(Throughput::Bytes((MESSAGES * size) as u64));
+        let buffer = incoming(size);
+        group.bench_with_input(BenchmarkId::new("decode", size), &buffer, |b, buffer| {
+            b.iter(|| decode_all(buffer.clone()))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("decode-copied", size),
+            &buffer,
+            |b, buffer| b.iter(|| decode_copied(buffer.clone())),
+        );
+        let body = body(size);
+        group.bench_with_input(BenchmarkId::new("encode", size), &body, |b, body| {
+            b.iter(|| encode_chained(body))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("encode-buffered", size),
+            &body,
+            |b, body| b.iter(|| encode_all(body)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bodies);
+
+fn main() {
+    report_allocations();
+    benches();
+    criterion::Criterion::default()
+        .configure_from_args()
+        .final_summary();
+}
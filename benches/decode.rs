@@ -0,0 +1,61 @@
+//! Decoding large frames which arrive a little at a time, as they do when
+//! read from a socket
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio_util::codec::Decoder;
+
+use tokio_stomp_2::client::ClientCodec;
+
+const CHUNK: usize = 4 * 1024;
+
+/// A MESSAGE frame with a body of `size` bytes, with or without content-length
+fn message(size: usize, content_length: bool) -> Vec<u8> {
+    let mut frame = b"MESSAGE\ndestination:/topic/feed\nmessage-id:1\nsubscription:0\n".to_vec();
+    if content_length {
+        frame.extend_from_slice(format!("content-length:{}\n", size).as_bytes());
+    }
+    frame.push(b'\n');
+    frame.extend((0..size).map(|i| b'a' + (i % 26) as u8));
+    frame.push(0);
+    frame
+}
+
+/// Feed `frame` to a codec `CHUNK` bytes at a time, decoding after each one
+fn decode_in_chunks(frame: &[u8]) {
+    let mut codec = ClientCodec::new();
+    let mut buffer = BytesMut::new();
+    let mut decoded = None;
+    for chunk in frame.chunks(CHUNK) {
+        buffer.extend_from_slice(chunk);
+        if let Some(msg) = codec.decode(&mut buffer).unwrap() {
+            decoded = Some(msg);
+        }
+    }
+    assert!(decoded.is_some());
+}
+
+fn chunked(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode in 4 KiB chunks");
+    group.sample_size(10);
+    for size in [1 << 20, 4 << 20] {
+        for content_length in [true, false] {
+            let frame = message(size, content_length);
+            let name = if content_length {
+                "content-length"
+            } else {
+                "NUL-terminated"
+            };
+            group.throughput(Throughput::Bytes(frame.len() as u64));
+            group.bench_with_input(
+                BenchmarkId::new(name, format!("{} MiB", size >> 20)),
+                &frame,
+                |b, frame| b.iter(|| decode_in_chunks(frame)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, chunked);
+criterion_main!(benches);
@@ -0,0 +1,37 @@
+use futures::prelude::*;
+use tokio_stomp_2::client::ConnectOptions;
+use tokio_stomp_2::{Client, FromServer, StompError, ToServer};
+
+// The example subscribes to a destination through a `Client` handle, sends a
+// message to it from a second handle and prints what the subscription receives.
+
+// You can start a simple STOMP server with docker:
+// `docker run -p 61613:61613 rmohr/activemq:latest`
+
+#[tokio::main]
+async fn main() -> Result<(), StompError> {
+    let client = Client::connect(&ConnectOptions::new("127.0.0.1:61613")).await?;
+    let mut subscription = client.subscribe("rusty").await?;
+
+    let sender = client.clone();
+    tokio::spawn(async move {
+        sender
+            .send(ToServer::Send {
+                destination: "rusty".into(),
+                transaction: None,
+                headers: vec![],
+                body: Some("Hello there rustaceans!".into()),
+            })
+            .await
+    });
+
+    if let Some(msg) = subscription.next().await {
+        if let FromServer::Message { body, .. } = msg?.into_message().content {
+            println!(
+                "Message received: {:?}",
+                String::from_utf8_lossy(&body.unwrap_or_default())
+            );
+        }
+    }
+    client.disconnect().await
+}
@@ -11,7 +11,7 @@ use tokio_stomp_2::*;
 // `docker run -p 61613:61613 rmohr/activemq:latest`
 
 async fn client(listens: &str, sends: &str, msg: &[u8]) -> Result<(), anyhow::Error> {
-    let mut conn = client::connect("127.0.0.1:61613", None, None).await?;
+    let mut conn = client::connect("127.0.0.1:61613", None, None, None).await?;
     conn.send(client::subscribe(listens, "myid")).await?;
 
     loop {
@@ -20,14 +20,14 @@ async fn client(listens: &str, sends: &str, msg: &[u8]) -> Result<(), anyhow::Er
                 destination: sends.into(),
                 transaction: None,
                 headers: vec![],
-                body: Some(msg.to_vec()),
+                body: Some(msg.to_vec().into()),
             }
             .into(),
         )
         .await?;
         let msg = conn.next().await.transpose()?;
         if let Some(FromServer::Message { body, .. }) = msg.as_ref().map(|m| &m.content) {
-            println!("{}", String::from_utf8_lossy(&body.as_ref().unwrap()));
+            println!("{}", String::from_utf8_lossy(body.as_ref().unwrap()));
         } else {
             anyhow::bail!("Unexpected: {:?}", msg)
         }
@@ -8,7 +8,7 @@ use tokio_stomp_2::FromServer;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let mut conn = client::connect("127.0.0.1:61613", None, None)
+    let mut conn = client::connect("127.0.0.1:61613", None, None, None)
         .await
         .unwrap();
 
@@ -8,7 +8,7 @@ use tokio_stomp_2::ToServer;
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let mut conn = client::connect("127.0.0.1:61613", None, None)
+    let mut conn = client::connect("127.0.0.1:61613", None, None, None)
         .await
         .unwrap();
 
@@ -17,7 +17,7 @@ async fn main() -> Result<(), std::io::Error> {
             destination: "queue.test".into(),
             transaction: None,
             headers: vec![],
-            body: Some(b"Hello there rustaceans!".to_vec()),
+            body: Some("Hello there rustaceans!".into()),
         }
         .into(),
     )
@@ -1,60 +1,400 @@
-use std::net::ToSocketAddrs;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::channel::mpsc;
 use futures::prelude::*;
+use futures::ready;
 use futures::sink::SinkExt;
 
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf};
 use tokio::net::TcpStream;
-use tokio_util::codec::{Decoder, Encoder, Framed};
-
-pub type ClientTransport = Framed<TcpStream, ClientCodec>;
+use tokio::task::JoinHandle;
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead};
 
 use crate::frame;
+use crate::heartbeat::{self, HeartbeatMonitor};
+use crate::tcp;
+
+use crate::{FromServer, Message, Result, StompError, ToServer};
+
+/// Number of outgoing frames which may be queued for the writer task
+const OUTGOING_BUFFER: usize = 32;
 
-use crate::{FromServer, Message, Result, ToServer};
+/// Source of the receipt ids used by [`ClientTransport::disconnect`]
+static NEXT_DISCONNECT: AtomicU64 = AtomicU64::new(0);
 
 /// Connect to a STOMP server via TCP, including the connection handshake.
-/// If successful, returns a tuple of a message stream and a sender,
-/// which may be used to receive and send messages respectively.
+/// If successful, returns a [`ClientTransport`], which is a `Stream` of
+/// messages from the server and a `Sink` for messages to it, and can be
+/// `split` into the two.
+///
+/// `heartbeat` is the desired `(outgoing, incoming)` heart-beat interval in
+/// milliseconds. The intervals are negotiated with the server, after which
+/// heart-beats are sent in the background whenever the connection is idle, and
+/// the stream fails with a timeout error if the server stops sending.
+///
+/// This is a shorthand for [`ConnectOptions`], which offers more control.
 pub async fn connect(
     address: &str,
     login: Option<String>,
     passcode: Option<String>,
+    heartbeat: Option<(u32, u32)>,
 ) -> Result<ClientTransport> {
-    let addr = address.to_socket_addrs().unwrap().next().unwrap();
-    let tcp = TcpStream::connect(&addr).await?;
-    let mut transport = ClientCodec.framed(tcp);
-    client_handshake(&mut transport, address.to_string(), login, passcode).await?;
-    Ok(transport)
+    let mut options = ConnectOptions::new(address);
+    options.login = login;
+    options.passcode = passcode;
+    options.heartbeat = heartbeat;
+    options.connect().await
 }
 
-async fn client_handshake(
-    transport: &mut ClientTransport,
-    host: String,
-    login: Option<String>,
-    passcode: Option<String>,
-) -> Result<()> {
+/// Perform the connection handshake over a stream which is already connected
+/// to the server, such as a Unix socket, a tunnel or an in-memory pipe.
+///
+/// The address in `options` is only used to derive the virtual host when none
+/// is set. The result is the framed stream, along with what was agreed with
+/// the server. Nothing is spawned, so heart-beats are left to the caller; wrap
+/// the stream with [`ClientTransport::new`] to have them handled as for [`connect`].
+pub async fn connect_with<S>(
+    stream: S,
+    options: &ConnectOptions,
+) -> Result<(Framed<S, ClientCodec>, SessionInfo)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut transport = options.codec.clone().framed(stream);
+    let session = with_timeout(
+        options.handshake_timeout,
+        client_handshake(&mut transport, options),
+        "waiting for CONNECTED",
+    )
+    .await??;
+    Ok((transport, session))
+}
+
+/// Split `host:port` into its parts. An IPv6 address must be written in
+/// brackets, as `[::1]:61613`, to carry a port; the brackets are removed.
+/// Returns `None` if the brackets are not closed or followed by junk.
+pub(crate) fn split_host_port(address: &str) -> Option<(&str, Option<&str>)> {
+    if let Some(bracketed) = address.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']')?;
+        return match rest {
+            "" => Some((host, None)),
+            _ => rest.strip_prefix(':').map(|port| (host, Some(port))),
+        };
+    }
+    match address.split_once(':') {
+        // More than one colon is an IPv6 address without a port
+        Some((host, port)) if !port.contains(':') => Some((host, Some(port))),
+        _ => Some((address, None)),
+    }
+}
+
+/// Options for establishing a STOMP connection.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use std::time::Duration;
+/// use tokio_stomp_2::client::ConnectOptions;
+///
+/// let conn = ConnectOptions::new("broker.local:61613")
+///     .host("/production")
+///     .credentials("guest", "guest")
+///     .heartbeat(10_000, 10_000)
+///     .header("client-id", "my-app")
+///     .connect_timeout(Duration::from_secs(5))
+///     .connect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ConnectOptions {
+    address: String,
+    pub(crate) host: Option<String>,
+    pub(crate) login: Option<String>,
+    pub(crate) passcode: Option<String>,
+    pub(crate) accept_versions: Vec<String>,
+    pub(crate) heartbeat: Option<(u32, u32)>,
+    headers: Vec<(String, String)>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) handshake_timeout: Option<Duration>,
+    stomp: bool,
+    happy_eyeballs: bool,
+    codec: ClientCodec,
+}
+
+impl ConnectOptions {
+    /// Options for connecting to the server at `address`, given as `host:port`
+    pub fn new(address: impl Into<String>) -> ConnectOptions {
+        ConnectOptions {
+            address: address.into(),
+            host: None,
+            login: None,
+            passcode: None,
+            accept_versions: vec!["1.2".into()],
+            heartbeat: None,
+            headers: vec![],
+            connect_timeout: None,
+            handshake_timeout: None,
+            stomp: false,
+            happy_eyeballs: false,
+            codec: ClientCodec::new(),
+        }
+    }
+
+    /// The virtual host sent in the `host` header.
+    /// Defaults to the host name part of the address.
+    pub fn host(mut self, host: impl Into<String>) -> ConnectOptions {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// The `login` and `passcode` to authenticate with
+    pub fn credentials(
+        mut self,
+        login: impl Into<String>,
+        passcode: impl Into<String>,
+    ) -> ConnectOptions {
+        self.login = Some(login.into());
+        self.passcode = Some(passcode.into());
+        self
+    }
+
+    /// The protocol versions to offer in `accept-version`. Defaults to `1.2` only.
+    pub fn accept_versions<I, V>(mut self, versions: I) -> ConnectOptions
+    where
+        I: IntoIterator<Item = V>,
+        V: Into<String>,
+    {
+        self.accept_versions = versions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The desired outgoing and incoming heart-beat intervals, in milliseconds
+    pub fn heartbeat(mut self, outgoing: u32, incoming: u32) -> ConnectOptions {
+        self.heartbeat = Some((outgoing, incoming));
+        self
+    }
+
+    /// An additional header for the CONNECT frame, such as `client-id`
+    pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> ConnectOptions {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Give up on an address if the TCP connection is not established within
+    /// `timeout`. Applies to each address the host name resolves to.
+    pub fn connect_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up if the server has not replied to CONNECT within `timeout`
+    pub fn handshake_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Send the STOMP command, introduced in STOMP 1.1, instead of CONNECT
+    pub fn use_stomp_command(mut self, stomp: bool) -> ConnectOptions {
+        self.stomp = stomp;
+        self
+    }
+
+    /// Race connection attempts to the resolved IPv6 and IPv4 addresses
+    /// ("Happy Eyeballs") instead of trying them one after the other
+    pub fn happy_eyeballs(mut self, enabled: bool) -> ConnectOptions {
+        self.happy_eyeballs = enabled;
+        self
+    }
+
+    /// The codec to read frames from the server with, which sets the limits
+    /// on their size. Defaults to [`ClientCodec::new`].
+    pub fn codec(mut self, codec: ClientCodec) -> ConnectOptions {
+        self.codec = codec;
+        self
+    }
+
+    /// Connect to the server, including the connection handshake.
+    /// Every address the host name resolves to is tried before giving up.
+    pub async fn connect(&self) -> Result<ClientTransport> {
+        let tcp = self.connect_tcp().await?;
+        self.handshake(tcp).await
+    }
+
+    pub(crate) async fn connect_tcp(&self) -> Result<TcpStream> {
+        tcp::connect(&self.address, self.connect_timeout, self.happy_eyeballs).await
+    }
+
+    /// Perform the STOMP handshake over an established stream
+    pub(crate) async fn handshake<S>(&self, stream: S) -> Result<ClientTransport<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (transport, session) = connect_with(stream, self).await?;
+        Ok(ClientTransport::new(transport, session))
+    }
+
+    pub(crate) fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The host name part of the address, without the brackets of an IPv6 literal
+    pub(crate) fn host_name(&self) -> &str {
+        match split_host_port(&self.address) {
+            Some((host, _)) => host,
+            None => &self.address,
+        }
+    }
+
+    fn virtual_host(&self) -> String {
+        match &self.host {
+            Some(host) => host.clone(),
+            None => self.host_name().into(),
+        }
+    }
+}
+
+impl fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectOptions")
+            .field("address", &self.address)
+            .field("host", &self.host)
+            .field("login", &self.login)
+            // Redacted, as when a `StompUrl` is displayed
+            .field("passcode", &self.passcode.as_ref().map(|_| "***"))
+            .field("accept_versions", &self.accept_versions)
+            .field("heartbeat", &self.heartbeat)
+            .field("headers", &self.headers)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("stomp", &self.stomp)
+            .field("happy_eyeballs", &self.happy_eyeballs)
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    future: F,
+    message: &'static str,
+) -> Result<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| StompError::Timeout(message)),
+        None => Ok(future.await),
+    }
+}
+
+/// What was agreed with the server during the connection handshake
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// The protocol version chosen by the server
+    pub version: String,
+    /// The session id assigned by the server
+    pub session: Option<String>,
+    /// The broker's name and version, from the `server` header
+    pub server: Option<ServerInfo>,
+    /// How often heart-beats are sent to the server, if at all
+    pub outgoing_heartbeat: Option<Duration>,
+    /// How often heart-beats are expected from the server, if at all
+    pub incoming_heartbeat: Option<Duration>,
+    /// Any other headers of the CONNECTED frame
+    pub headers: Vec<(String, String)>,
+}
+
+/// The `server` header of a CONNECTED frame, such as `ActiveMQ/5.18.3`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl ServerInfo {
+    /// Parse `name ["/" version] *(comment)`, ignoring the comments
+    fn parse(server: &str) -> ServerInfo {
+        let product = server.split_whitespace().next().unwrap_or_default();
+        match product.split_once('/') {
+            Some((name, version)) => ServerInfo {
+                name: name.into(),
+                version: Some(version.into()),
+            },
+            None => ServerInfo {
+                name: product.into(),
+                version: None,
+            },
+        }
+    }
+}
+
+async fn client_handshake<S>(
+    transport: &mut Framed<S, ClientCodec>,
+    options: &ConnectOptions,
+) -> Result<SessionInfo>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let connect = Message {
         content: ToServer::Connect {
-            accept_version: String::from("1.2"),
-            host,
-            login,
-            passcode,
-            heartbeat: None,
+            accept_version: options.accept_versions.join(","),
+            host: options.virtual_host(),
+            login: options.login.clone(),
+            passcode: options.passcode.clone(),
+            heartbeat: options.heartbeat,
+            stomp: options.stomp,
         },
-        extra_headers: vec![],
+        extra_headers: options
+            .headers
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect(),
     };
     // Send the message
     transport.send(connect).await?;
     // Receive reply
-    let msg = transport.next().await.transpose()?;
-    if let Some(FromServer::Connected { .. }) = msg.as_ref().map(|m| &m.content) {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!(
-            "Handshake error, unexpected reply: {:?}",
-            msg
-        ))
+    let msg = match transport.next().await {
+        Some(msg) => msg?,
+        None => return Err(StompError::ConnectionClosed),
+    };
+    match &msg.content {
+        FromServer::Connected {
+            version,
+            session,
+            server,
+            heartbeat: server_heartbeat,
+        } => {
+            if !options.accept_versions.contains(version) {
+                return Err(StompError::UnsupportedVersion(version.clone()));
+            }
+            let (outgoing, incoming) = heartbeat::negotiate(
+                options.heartbeat.unwrap_or((0, 0)),
+                server_heartbeat.unwrap_or((0, 0)),
+            );
+            let headers = msg
+                .extra_headers
+                .iter()
+                .map(|(k, v)| {
+                    let k = String::from_utf8_lossy(k).into_owned();
+                    (k, String::from_utf8_lossy(v).into_owned())
+                })
+                .collect();
+            Ok(SessionInfo {
+                version: version.clone(),
+                session: session.clone(),
+                server: server.as_deref().map(ServerInfo::parse),
+                outgoing_heartbeat: outgoing,
+                incoming_heartbeat: incoming,
+                headers,
+            })
+        }
+        FromServer::Error { .. } => Err(StompError::Rejected(Box::new(msg))),
+        _ => Err(StompError::UnexpectedFrame(Box::new(msg))),
     }
 }
 
@@ -69,31 +409,633 @@ pub fn subscribe(dest: &str, id: &str) -> Message<ToServer> {
     .into()
 }
 
-pub struct ClientCodec;
+/// A connected STOMP session. Implements `Stream` for messages from the server
+/// and `Sink` for messages to the server, and can be `split` into the two.
+///
+/// Outgoing frames are written by a background task, which also takes care of
+/// sending heart-beats while the connection is idle.
+///
+/// `S` is the underlying byte stream, a plain TCP connection by default.
+pub struct ClientTransport<S = TcpStream> {
+    stream: FramedRead<HeartbeatMonitor<ReadHalf<S>>, ClientCodec>,
+    sink: mpsc::Sender<Message<ToServer>>,
+    writer: Option<JoinHandle<Result<()>>>,
+    session: SessionInfo,
+}
+
+impl<S> ClientTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Take over a connection on which the handshake is done, as returned by
+    /// [`connect_with`], and start the background task which writes frames
+    /// and heart-beats. Must be called within a tokio runtime.
+    pub fn new(transport: Framed<S, ClientCodec>, session: SessionInfo) -> ClientTransport<S> {
+        let (outgoing, incoming) = (session.outgoing_heartbeat, session.incoming_heartbeat);
+        let parts = transport.into_parts();
+        let (read, write) = tokio::io::split(parts.io);
+        let codec = parts.codec.clone();
+        let mut stream = FramedRead::new(HeartbeatMonitor::new(read, incoming), parts.codec);
+        stream.read_buffer_mut().extend_from_slice(&parts.read_buf);
+        let (sink, rx) = mpsc::channel(OUTGOING_BUFFER);
+        let writer = tokio::spawn(heartbeat::write_loop(write, codec, rx, outgoing));
+        ClientTransport {
+            stream,
+            sink,
+            writer: Some(writer),
+            session,
+        }
+    }
+
+    /// Shut the session down gracefully, as the spec describes: once every
+    /// queued frame is written, send DISCONNECT with a receipt, wait up to
+    /// `timeout` for the server's RECEIPT and then shut down the write half
+    /// of the connection. Frames received in the meantime are discarded.
+    ///
+    /// The connection is shut down even if the receipt does not arrive in
+    /// time. Any frame sent afterwards fails with [`StompError::ConnectionClosed`].
+    pub async fn disconnect(&mut self, timeout: Duration) -> Result<()> {
+        let receipt = format!(
+            "disconnect-{}",
+            NEXT_DISCONNECT.fetch_add(1, Ordering::Relaxed)
+        );
+        let disconnect = ToServer::Disconnect {
+            receipt: Some(receipt.clone()),
+        };
+        let confirmed = match self.send(disconnect.into()).await {
+            Ok(()) => match tokio::time::timeout(timeout, self.receipt(&receipt)).await {
+                Ok(res) => res,
+                Err(_) => Err(StompError::Timeout("waiting for DISCONNECT receipt")),
+            },
+            Err(e) => Err(e),
+        };
+        let closed = self.close().await;
+        confirmed.and(closed)
+    }
+
+    /// Read frames until the RECEIPT for `id`
+    async fn receipt(&mut self, id: &str) -> Result<()> {
+        while let Some(msg) = self.next().await {
+            if let FromServer::Receipt { receipt_id } = msg?.content {
+                if receipt_id == id {
+                    return Ok(());
+                }
+            }
+        }
+        Err(StompError::ConnectionClosed)
+    }
+}
+
+impl<S> ClientTransport<S> {
+    /// What was agreed with the server when connecting
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    /// Called once the writer task has gone away, to find out why
+    fn poll_writer_error(&mut self, cx: &mut Context<'_>) -> Poll<StompError> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return Poll::Ready(StompError::ConnectionClosed),
+        };
+        let res = ready!(Pin::new(writer).poll(cx));
+        self.writer = None;
+        Poll::Ready(match res {
+            Ok(Err(e)) => e,
+            _ => StompError::ConnectionClosed,
+        })
+    }
+}
+
+impl<S: AsyncRead> Stream for ClientTransport<S> {
+    type Item = Result<Message<FromServer>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let res = ready!(Pin::new(&mut self.stream).poll_next(cx));
+        Poll::Ready(res.map(|res| {
+            res.map_err(|e| match self.stream.get_ref().expired() {
+                Some(timeout) => StompError::HeartbeatTimeout(timeout),
+                None => e,
+            })
+        }))
+    }
+}
+
+impl<S> Sink<Message<ToServer>> for ClientTransport<S> {
+    type Error = StompError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match ready!(self.sink.poll_ready(cx)) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(_) => self.poll_writer_error(cx).map(Err),
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message<ToServer>) -> Result<()> {
+        self.sink
+            .start_send(item)
+            .map_err(|_| StompError::ConnectionClosed)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.sink)
+            .poll_flush(cx)
+            .map_err(|_| StompError::ConnectionClosed)
+    }
+
+    /// Waits until all queued frames are written and the connection is shut down
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // Closing the channel ends the writer task once it has drained the queue
+        let _ = ready!(Pin::new(&mut self.sink).poll_close(cx));
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return Poll::Ready(Ok(())),
+        };
+        let res = ready!(Pin::new(writer).poll(cx));
+        self.writer = None;
+        Poll::Ready(res.unwrap_or(Err(StompError::ConnectionClosed)))
+    }
+}
+
+/// The client side of the STOMP codec. Encodes frames sent by the client and
+/// decodes frames sent by the server.
+///
+/// A frame from the server which crosses one of the limits fails to decode
+/// with a distinct error as soon as that is known, rather than once it has
+/// been buffered in full. The defaults are those ActiveMQ applies.
+///
+/// The codec keeps its place in a partly received frame between calls, so a
+/// large frame arriving over many reads is only scanned once.
+///
+/// ```
+/// use tokio_stomp_2::client::ClientCodec;
+///
+/// let codec = ClientCodec::new()
+///     .max_frame_size(1024 * 1024)
+///     .max_headers(64);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientCodec {
+    parser: frame::Parser,
+    crlf: bool,
+}
+
+impl ClientCodec {
+    /// A codec with the default limits
+    pub fn new() -> ClientCodec {
+        ClientCodec {
+            parser: frame::Parser::new(frame::Limits::default()),
+            crlf: false,
+        }
+    }
+
+    /// The largest frame accepted, in bytes from the command up to and
+    /// including the terminating NUL. Defaults to 100 MiB.
+    pub fn max_frame_size(mut self, bytes: usize) -> ClientCodec {
+        self.parser.limits.frame_size = bytes;
+        self
+    }
+
+    /// The most headers accepted in a frame. Defaults to 1000.
+    pub fn max_headers(mut self, count: usize) -> ClientCodec {
+        self.parser.limits.headers = count;
+        self
+    }
+
+    /// The longest header line accepted, in bytes. Defaults to 10 KiB.
+    pub fn max_header_length(mut self, bytes: usize) -> ClientCodec {
+        self.parser.limits.header_length = bytes;
+        self
+    }
+
+    /// The longest command accepted, in bytes. Defaults to 1024.
+    pub fn max_command_length(mut self, bytes: usize) -> ClientCodec {
+        self.parser.limits.command_length = bytes;
+        self
+    }
+
+    /// End the lines of outgoing frames with CRLF rather than LF. Incoming
+    /// frames may use either.
+    pub fn crlf_line_endings(mut self, crlf: bool) -> ClientCodec {
+        self.crlf = crlf;
+        self
+    }
+
+    /// Encode `item` without copying its body. Only the command and headers
+    /// are written to a new buffer, which is chained with the body `Bytes`
+    /// and the terminating NUL. Writing the result with
+    /// `AsyncWriteExt::write_all_buf` uses vectored writes where the
+    /// connection supports them. [`ClientTransport`] sends frames this way.
+    pub fn encode_buf(&mut self, item: Message<ToServer>) -> impl Buf + Send + 'static {
+        let mut head = BytesMut::new();
+        item.to_frame().serialize_head(&mut head, self.eol());
+        let body = match item.content {
+            ToServer::Send {
+                body: Some(body), ..
+            } => body,
+            _ => Bytes::new(),
+        };
+        head.freeze().chain(body).chain(&b"\x00"[..])
+    }
+
+    fn eol(&self) -> &'static [u8] {
+        if self.crlf {
+            b"\r\n"
+        } else {
+            b"\n"
+        }
+    }
+}
+
+impl Default for ClientCodec {
+    fn default() -> ClientCodec {
+        ClientCodec::new()
+    }
+}
 
 impl Decoder for ClientCodec {
     type Item = Message<FromServer>;
-    type Error = anyhow::Error;
+    type Error = StompError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
-        let (item, offset) = match frame::parse_frame(src) {
-            Ok((remain, frame)) => (
-                Message::<FromServer>::from_frame(frame),
-                remain.as_ptr() as usize - src.as_ptr() as usize,
-            ),
-            Err(nom::Err::Incomplete(_)) => return Ok(None),
-            Err(e) => anyhow::bail!("Parse failed: {:?}", e),
-        };
-        src.advance(offset);
-        item.map(|v| Some(v))
+        frame::decode(src, &mut self.parser, Message::<FromServer>::from_frame)
     }
 }
 
 impl Encoder<Message<ToServer>> for ClientCodec {
-    type Error = anyhow::Error;
+    type Error = StompError;
 
     fn encode(&mut self, item: Message<ToServer>, dst: &mut BytesMut) -> Result<()> {
-        item.to_frame().serialize(dst);
+        item.to_frame().serialize_with_eol(dst, self.eol());
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerCodec;
+    use crate::testing::handshake;
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// Accepts one connection, completes the handshake advertising the given
+    /// `heart-beat` and hands back the raw socket
+    async fn accept_with_heartbeat(listener: TcpListener, heartbeat: (u32, u32)) -> TcpStream {
+        accept(listener, heartbeat).await.0
+    }
+
+    /// As `accept_with_heartbeat`, also returning the CONNECT frame
+    async fn accept(
+        listener: TcpListener,
+        heartbeat: (u32, u32),
+    ) -> (TcpStream, Message<ToServer>) {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let (server, connect) = handshake(tcp, Some(heartbeat)).await;
+        (server.into_inner(), connect)
+    }
+
+    #[tokio::test]
+    async fn sends_heartbeats_while_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(accept_with_heartbeat(listener, (0, 50)));
+        let _conn = connect(&address, None, None, Some((20, 0))).await.unwrap();
+        let mut tcp = server.await.unwrap();
+        let mut buf = [0; 2];
+        tokio::time::timeout(Duration::from_secs(1), tcp.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"\n\n");
+    }
+
+    #[tokio::test]
+    async fn fails_when_server_goes_quiet() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(accept_with_heartbeat(listener, (50, 0)));
+        let mut conn = connect(&address, None, None, Some((0, 20))).await.unwrap();
+        let _tcp = server.await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(1), conn.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(matches!(err, StompError::HeartbeatTimeout(_)));
+    }
+
+    #[tokio::test]
+    async fn connect_options_shape_the_connect_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(accept(listener, (0, 0)));
+        ConnectOptions::new(&address)
+            .credentials("guest", "secret")
+            .accept_versions(vec!["1.1", "1.2"])
+            .header("client-id", "durable-1")
+            .use_stomp_command(true)
+            .connect()
+            .await
+            .unwrap();
+        let (_, connect) = server.await.unwrap();
+        match connect.content {
+            ToServer::Connect {
+                accept_version,
+                host,
+                login,
+                passcode,
+                stomp,
+                ..
+            } => {
+                assert_eq!(accept_version, "1.1,1.2");
+                assert_eq!(host, "127.0.0.1");
+                assert_eq!(login.as_deref(), Some("guest"));
+                assert_eq!(passcode.as_deref(), Some("secret"));
+                assert!(stomp);
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+        assert_eq!(
+            connect.extra_headers,
+            vec![(b"client-id".to_vec(), b"durable-1".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    async fn session_info_describes_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut server = ServerCodec::new().framed(tcp);
+            server.next().await.unwrap().unwrap();
+            let connected = Message {
+                content: FromServer::Connected {
+                    version: "1.2".into(),
+                    session: Some("ID:broker-1".into()),
+                    server: Some("ActiveMQ/5.18.3 (Linux)".into()),
+                    heartbeat: Some((1000, 0)),
+                },
+                extra_headers: vec![(b"host-id".to_vec(), b"b1".to_vec())],
+            };
+            server.send(connected).await.unwrap();
+            // Keep the connection open
+            server.next().await;
+        });
+        let conn = ConnectOptions::new(&address)
+            .heartbeat(2000, 500)
+            .connect()
+            .await
+            .unwrap();
+        let session = conn.session();
+        assert_eq!(session.version, "1.2");
+        assert_eq!(session.session.as_deref(), Some("ID:broker-1"));
+        assert_eq!(
+            session.server,
+            Some(ServerInfo {
+                name: "ActiveMQ".into(),
+                version: Some("5.18.3".into()),
+            })
+        );
+        assert_eq!(session.outgoing_heartbeat, None);
+        assert_eq!(session.incoming_heartbeat, Some(Duration::from_secs(1)));
+        assert_eq!(session.headers, vec![("host-id".into(), "b1".into())]);
+    }
+
+    #[tokio::test]
+    async fn handshake_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // Accept the connection but never reply
+        let _server = tokio::spawn(async move { listener.accept().await });
+        let err = ConnectOptions::new(&address)
+            .host("vhost")
+            .handshake_timeout(Duration::from_millis(50))
+            .connect()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, StompError::Timeout(_)));
+    }
+
+    #[tokio::test]
+    async fn rejected_handshake_carries_error_frame() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut server = ServerCodec::new().framed(tcp);
+            server.next().await.unwrap().unwrap();
+            let error = FromServer::Error {
+                message: Some("Bad credentials".into()),
+                body: None,
+            };
+            server.send(error.into()).await.unwrap();
+        });
+        match connect(&address, None, None, None).await {
+            Err(StompError::Rejected(msg)) => match msg.content {
+                FromServer::Error { message, .. } => {
+                    assert_eq!(message.as_deref(), Some("Bad credentials"))
+                }
+                other => panic!("Unexpected frame: {:?}", other),
+            },
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Handshake should fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_over_any_stream() {
+        let (client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let (mut server, connect) = handshake(server, None).await;
+            match connect.content {
+                ToServer::Connect { host, .. } => assert_eq!(host, "memory"),
+                other => panic!("Unexpected frame: {:?}", other),
+            }
+            let subscribe = server.next().await.unwrap().unwrap();
+            assert!(matches!(subscribe.content, ToServer::Subscribe { .. }));
+            let message = FromServer::Message {
+                destination: "/q".into(),
+                message_id: "1".into(),
+                subscription: "s".into(),
+                ack: None,
+                headers: vec![],
+                body: Some(Bytes::from_static(b"hello")),
+            };
+            server.send(message.into()).await.unwrap();
+        });
+
+        let options = ConnectOptions::new("unused:0").host("memory");
+        let (mut conn, session) = connect_with(client, &options).await.unwrap();
+        assert_eq!(session.version, "1.2");
+        conn.send(subscribe("/q", "s")).await.unwrap();
+        match conn.next().await.unwrap().unwrap().content {
+            FromServer::Message { body, .. } => assert_eq!(body.as_deref(), Some(&b"hello"[..])),
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+        server.await.unwrap();
+    }
+
+    /// Connect to a server which collects every frame until the connection is
+    /// shut down. If `confirm` is set it answers DISCONNECT with a MESSAGE,
+    /// which the client should discard, and then the RECEIPT.
+    async fn disconnecting_server(
+        confirm: bool,
+    ) -> (ClientTransport, tokio::task::JoinHandle<Vec<ToServer>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut server = ServerCodec::new().framed(accept(listener, (0, 0)).await.0);
+            let mut received = vec![];
+            while let Some(msg) = server.next().await {
+                let msg = msg.unwrap().content;
+                if let ToServer::Disconnect {
+                    receipt: Some(receipt),
+                } = &msg
+                {
+                    if confirm {
+                        let late = FromServer::Message {
+                            destination: "/q".into(),
+                            message_id: "late".into(),
+                            subscription: "s".into(),
+                            ack: None,
+                            headers: vec![],
+                            body: None,
+                        };
+                        server.send(late.into()).await.unwrap();
+                        let receipt = FromServer::Receipt {
+                            receipt_id: receipt.clone(),
+                        };
+                        server.send(receipt.into()).await.unwrap();
+                    }
+                }
+                received.push(msg);
+            }
+            received
+        });
+        (connect(&address, None, None, None).await.unwrap(), server)
+    }
+
+    #[tokio::test]
+    async fn disconnect_waits_for_receipt_then_rejects_frames() {
+        let (mut conn, server) = disconnecting_server(true).await;
+        conn.send(subscribe("/q", "s")).await.unwrap();
+        conn.disconnect(Duration::from_secs(1)).await.unwrap();
+        assert!(matches!(
+            conn.send(subscribe("/r", "t")).await,
+            Err(StompError::ConnectionClosed)
+        ));
+        // The server saw the subscription, then DISCONNECT, then EOF
+        let received = server.await.unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(matches!(received[0], ToServer::Subscribe { .. }));
+        assert!(matches!(received[1], ToServer::Disconnect { .. }));
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_even_without_receipt() {
+        let (mut conn, server) = disconnecting_server(false).await;
+        let err = conn
+            .disconnect(Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, StompError::Timeout(_)));
+        assert_eq!(server.await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn encode_buf_leaves_the_body_where_it_is() {
+        let body = Bytes::from_static(b"a\x00b");
+        let chained = BytesMut::from(&b"pay"[..]).chain(&b"load"[..]);
+        let frames = [
+            ToServer::send("/q", body.clone()),
+            ToServer::send("/q", chained),
+            ToServer::Disconnect { receipt: None },
+        ];
+        for crlf in [false, true] {
+            let mut codec = ClientCodec::new().crlf_line_endings(crlf);
+            for frame in &frames {
+                let mut buffered = BytesMut::new();
+                codec.encode(frame.clone().into(), &mut buffered).unwrap();
+                let mut unbuffered = codec.encode_buf(frame.clone().into());
+                assert_eq!(unbuffered.copy_to_bytes(unbuffered.remaining()), buffered);
+            }
+        }
+
+        // The body is the last but one part, sharing memory with the original
+        let buf = ClientCodec::new().encode_buf(ToServer::send("/q", body.clone()).into());
+        let mut parts = [std::io::IoSlice::new(&[]); 4];
+        assert_eq!(buf.chunks_vectored(&mut parts), 3);
+        assert_eq!(parts[1].as_ptr(), body.as_ptr());
+        assert_eq!(&*parts[2], b"\x00");
+    }
+
+    #[test]
+    fn codec_refuses_frames_over_limits() {
+        let mut codec = ClientCodec::new()
+            .max_frame_size(80)
+            .max_headers(4)
+            .max_header_length(17)
+            .max_command_length(9);
+        let mut refuse = |input: &[u8]| {
+            let mut buffer = BytesMut::from(input);
+            codec.decode(&mut buffer).unwrap_err()
+        };
+        // Each limit is crossed before the frame is complete
+        assert!(matches!(
+            refuse(b"\nMESSAGEXYZ"),
+            StompError::CommandTooLong(9)
+        ));
+        assert!(matches!(
+            refuse(b"MESSAGE\r\na:1\r\nb:2\nc:3\nd:4\ne:"),
+            StompError::TooManyHeaders(4)
+        ));
+        assert!(matches!(
+            refuse(b"MESSAGE\ndestination:/queue/a"),
+            StompError::HeaderTooLong(17)
+        ));
+        assert!(matches!(
+            refuse(b"MESSAGE\ndestination:/q\nmessage-id:1\nsubscription:s\ncontent-length:99\n\n"),
+            StompError::FrameTooLarge(80)
+        ));
+        assert!(matches!(
+            refuse(b"MESSAGE\ndestination:/q\nmessage-id:1\nsubscription:s\n\n0123456789abcdefghijklmnopqrstu"),
+            StompError::FrameTooLarge(80)
+        ));
+
+        // A frame right at the limits decodes, lines counted without "\r\n"
+        let mut buffer = BytesMut::from(
+            &b"MESSAGE\r\ndestination:/q\r\nmessage-id:1\r\nsubscription:s\r\n\
+               content-length:4\r\n\r\n012\x00\x00"[..],
+        );
+        let msg = codec.decode(&mut buffer).unwrap().unwrap();
+        assert!(matches!(msg.content, FromServer::Message { .. }));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn host_name_strips_port_and_brackets() {
+        for (address, host) in [
+            ("broker.local:61613", "broker.local"),
+            ("broker.local", "broker.local"),
+            ("127.0.0.1:61613", "127.0.0.1"),
+            ("[::1]:61613", "::1"),
+            ("[::1]", "::1"),
+            ("::1", "::1"),
+            ("[fe80::1%eth0]:61613", "fe80::1%eth0"),
+        ] {
+            assert_eq!(
+                ConnectOptions::new(address).host_name(),
+                host,
+                "{}",
+                address
+            );
+        }
+        assert_eq!(split_host_port("[::1]:"), Some(("::1", Some(""))));
+        assert_eq!(split_host_port("[::1"), None);
+        assert_eq!(split_host_port("[::1]61613"), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::{FromServer, Message};
+
+/// The errors which may occur while talking to a STOMP server.
+///
+/// Variants may be added in future, and some only exist with a crate feature
+/// enabled, so matches on this enum need a wildcard arm.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum StompError {
+    /// The underlying connection failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// The received data is not a valid STOMP frame
+    #[error("Parse failed at byte {offset}: {reason}")]
+    Parse { offset: usize, reason: String },
+    /// A received frame is larger than the codec allows
+    #[error("Frame exceeds the limit of {0} bytes")]
+    FrameTooLarge(usize),
+    /// A received frame has more headers than the codec allows
+    #[error("Frame has more than {0} headers")]
+    TooManyHeaders(usize),
+    /// A received header line is longer than the codec allows
+    #[error("Header exceeds the limit of {0} bytes")]
+    HeaderTooLong(usize),
+    /// A received command line is longer than the codec allows
+    #[error("Command exceeds the limit of {0} bytes")]
+    CommandTooLong(usize),
+    /// A header required by the frame's command is missing
+    #[error("Expected header '{0}' missing")]
+    MissingHeader(String),
+    /// A header has a value which could not be understood
+    #[error("Invalid value for header '{header}': {value}")]
+    InvalidHeader { header: String, value: String },
+    /// The frame's command is not one this side of the connection accepts
+    #[error("Frame not recognized: {0:?}")]
+    UnknownCommand(String),
+    /// The host name could not be resolved
+    #[error("Failed to resolve {address}: {source}")]
+    Resolve { address: String, source: io::Error },
+    /// No connection could be established to any of the resolved addresses
+    #[error("Failed to connect to {address}: tried {}", format_attempts(.attempts))]
+    Connect {
+        address: String,
+        attempts: Vec<(SocketAddr, io::Error)>,
+    },
+    /// A connection or handshake timeout elapsed
+    #[error("Timed out {0}")]
+    Timeout(&'static str),
+    /// Nothing was received from the server within the negotiated heart-beat interval
+    #[error("No heart-beat received from server in {0:?}")]
+    HeartbeatTimeout(Duration),
+    /// The server answered CONNECT with an ERROR frame
+    #[error("Server rejected connection: {}", error_message(.0))]
+    Rejected(Box<Message<FromServer>>),
+    /// The server answered CONNECT with something other than CONNECTED or ERROR
+    #[error("Handshake error, unexpected reply: {0:?}")]
+    UnexpectedFrame(Box<Message<FromServer>>),
+    /// The server chose a protocol version which was not offered
+    #[error("Server chose unsupported protocol version {0}")]
+    UnsupportedVersion(String),
+    /// The server sent an ERROR frame
+    #[error("Server error: {}", error_message(.0))]
+    ServerError(Box<Message<FromServer>>),
+    /// A connection URL could not be parsed
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    /// The connection was closed
+    #[error("Connection closed")]
+    ConnectionClosed,
+    /// A [`Failover`](crate::Failover) was given no endpoints to connect to
+    #[error("No endpoints to connect to")]
+    NoEndpoints,
+    /// The TLS configuration was invalid, or the TLS handshake failed
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
+    /// The WebSocket handshake failed
+    #[cfg(feature = "websocket")]
+    #[error("WebSocket error: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+}
+
+impl StompError {
+    /// A copy of the error, to hand to several subscriptions or keep for
+    /// reporting. I/O errors cannot be cloned, so they are copied by kind
+    /// and message.
+    pub(crate) fn duplicate(&self) -> StompError {
+        let io = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+        match self {
+            StompError::Io(e) => StompError::Io(io(e)),
+            StompError::Parse { offset, reason } => StompError::Parse {
+                offset: *offset,
+                reason: reason.clone(),
+            },
+            StompError::FrameTooLarge(limit) => StompError::FrameTooLarge(*limit),
+            StompError::TooManyHeaders(limit) => StompError::TooManyHeaders(*limit),
+            StompError::HeaderTooLong(limit) => StompError::HeaderTooLong(*limit),
+            StompError::CommandTooLong(limit) => StompError::CommandTooLong(*limit),
+            StompError::MissingHeader(header) => StompError::MissingHeader(header.clone()),
+            StompError::InvalidHeader { header, value } => StompError::InvalidHeader {
+                header: header.clone(),
+                value: value.clone(),
+            },
+            StompError::UnknownCommand(command) => StompError::UnknownCommand(command.clone()),
+            StompError::Resolve { address, source } => StompError::Resolve {
+                address: address.clone(),
+                source: io(source),
+            },
+            StompError::Connect { address, attempts } => StompError::Connect {
+                address: address.clone(),
+                attempts: attempts.iter().map(|(addr, e)| (*addr, io(e))).collect(),
+            },
+            StompError::Timeout(what) => StompError::Timeout(what),
+            StompError::HeartbeatTimeout(timeout) => StompError::HeartbeatTimeout(*timeout),
+            StompError::Rejected(msg) => StompError::Rejected(msg.clone()),
+            StompError::UnexpectedFrame(msg) => StompError::UnexpectedFrame(msg.clone()),
+            StompError::UnsupportedVersion(version) => {
+                StompError::UnsupportedVersion(version.clone())
+            }
+            StompError::ServerError(msg) => StompError::ServerError(msg.clone()),
+            StompError::InvalidUrl(reason) => StompError::InvalidUrl(reason.clone()),
+            StompError::ConnectionClosed => StompError::ConnectionClosed,
+            StompError::NoEndpoints => StompError::NoEndpoints,
+            #[cfg(feature = "tls")]
+            StompError::Tls(e) => StompError::Tls(e.clone()),
+            // Neither can WebSocket errors
+            #[cfg(feature = "websocket")]
+            StompError::WebSocket(e) => StompError::Io(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+fn format_attempts(attempts: &[(SocketAddr, io::Error)]) -> String {
+    let tried: Vec<String> = attempts
+        .iter()
+        .map(|(addr, e)| format!("{} ({})", addr, e))
+        .collect();
+    tried.join(", ")
+}
+
+fn error_message(msg: &Message<FromServer>) -> String {
+    match &msg.content {
+        FromServer::Error {
+            message: Some(message),
+            ..
+        } => message.clone(),
+        FromServer::Error {
+            body: Some(body), ..
+        } => String::from_utf8_lossy(body).into_owned(),
+        other => format!("{:?}", other),
+    }
+}
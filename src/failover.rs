@@ -0,0 +1,340 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{self, BoxFuture};
+use futures::prelude::*;
+use tokio::net::TcpStream;
+
+use crate::client::{ClientTransport, ConnectOptions};
+use crate::reconnect::random_fraction;
+use crate::session::Connector;
+use crate::{Result, StompError};
+
+/// A list of brokers for a [`Client`](crate::Client) to fail over between,
+/// like ActiveMQ's `failover:(tcp://a,tcp://b)`.
+///
+/// Endpoints are tried in order, or in random order, with the ones which
+/// failed most recently moved to the back. The first endpoint is the primary.
+#[derive(Debug, Clone)]
+pub struct Failover {
+    endpoints: Vec<ConnectOptions>,
+    randomize: bool,
+    return_to_primary: Option<Duration>,
+}
+
+impl Failover {
+    /// Fail over between `endpoints`, the first of which is the primary
+    pub fn new(endpoints: impl IntoIterator<Item = ConnectOptions>) -> Failover {
+        Failover {
+            endpoints: endpoints.into_iter().collect(),
+            randomize: false,
+            return_to_primary: None,
+        }
+    }
+
+    /// Try the endpoints in random order, to spread clients across brokers
+    pub fn randomize(mut self, randomize: bool) -> Failover {
+        self.randomize = randomize;
+        self
+    }
+
+    /// While connected to another endpoint, try the primary every `interval`
+    /// and move back to it once it is reachable. Subscriptions are re-issued
+    /// on the primary before the old connection is closed, and messages and
+    /// receipts still arriving on the old connection are passed on until the
+    /// server confirms its DISCONNECT.
+    pub fn return_to_primary(mut self, interval: Duration) -> Failover {
+        self.return_to_primary = Some(interval);
+        self
+    }
+
+    pub(crate) fn endpoints(&self) -> &[ConnectOptions] {
+        &self.endpoints
+    }
+}
+
+/// The state of one endpoint of a [`Client`](crate::Client)
+#[derive(Debug, Clone)]
+pub struct EndpointHealth {
+    /// The endpoint's address
+    pub address: String,
+    /// Whether the client is currently connected to this endpoint
+    pub active: bool,
+    /// Connection attempts which failed since the last successful one
+    pub consecutive_failures: u32,
+    /// The error of the last failed attempt
+    pub last_error: Option<Arc<StompError>>,
+}
+
+pub(crate) type Health = Arc<Mutex<Vec<EndpointHealth>>>;
+
+pub(crate) struct FailoverConnector {
+    failover: Arc<Failover>,
+    health: Health,
+}
+
+impl FailoverConnector {
+    pub(crate) fn new(failover: Failover) -> FailoverConnector {
+        let health = failover
+            .endpoints
+            .iter()
+            .map(|options| EndpointHealth {
+                address: options.address().into(),
+                active: false,
+                consecutive_failures: 0,
+                last_error: None,
+            })
+            .collect();
+        FailoverConnector {
+            failover: Arc::new(failover),
+            health: Arc::new(Mutex::new(health)),
+        }
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        self.health.clone()
+    }
+
+    /// The order in which to try the endpoints
+    fn candidates(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.failover.endpoints.len()).collect();
+        if self.failover.randomize {
+            for i in (1..order.len()).rev() {
+                let j = (random_fraction() * (i + 1) as f64) as usize;
+                order.swap(i, j);
+            }
+        }
+        let health = self.health.lock().unwrap();
+        order.sort_by_key(|&i| health[i].consecutive_failures);
+        order
+    }
+}
+
+/// Record the outcome of an attempt on endpoint `index`
+fn record(health: &Health, index: usize, res: std::result::Result<(), &StompError>) {
+    let mut health = health.lock().unwrap();
+    match res {
+        Ok(()) => {
+            for (i, endpoint) in health.iter_mut().enumerate() {
+                endpoint.active = i == index;
+            }
+            health[index].consecutive_failures = 0;
+        }
+        Err(e) => {
+            let endpoint = &mut health[index];
+            endpoint.consecutive_failures += 1;
+            endpoint.last_error = Some(Arc::new(e.duplicate()));
+        }
+    }
+}
+
+impl Connector<TcpStream> for FailoverConnector {
+    fn connect(&mut self) -> BoxFuture<'static, Result<(ClientTransport, String)>> {
+        let candidates = self.candidates();
+        let failover = self.failover.clone();
+        let health = self.health.clone();
+        async move {
+            health
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .for_each(|e| e.active = false);
+            let mut last_error = StompError::ConnectionClosed;
+            for index in candidates {
+                let options = &failover.endpoints[index];
+                let res = options.connect().await;
+                record(&health, index, res.as_ref().map(|_| ()));
+                match res {
+                    Ok(transport) => return Ok((transport, options.address().into())),
+                    Err(e) => last_error = e,
+                }
+            }
+            Err(last_error)
+        }
+        .boxed()
+    }
+
+    fn upgrade(&mut self) -> BoxFuture<'static, (ClientTransport, String)> {
+        let on_primary = self.health.lock().unwrap().first().map(|e| e.active);
+        let interval = match (self.failover.return_to_primary, on_primary) {
+            (Some(interval), Some(false)) => interval,
+            _ => return future::pending().boxed(),
+        };
+        let failover = self.failover.clone();
+        let health = self.health.clone();
+        async move {
+            let primary = &failover.endpoints[0];
+            loop {
+                tokio::time::sleep(interval).await;
+                // Success is recorded once the client has switched over
+                match primary.connect().await {
+                    Ok(transport) => return (transport, primary.address().into()),
+                    Err(e) => record(&health, 0, Err(&e)),
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn switched(&mut self) {
+        record(&self.health, 0, Ok(()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerCodec;
+    use crate::testing::handshake;
+    use crate::{Client, ConnectionEvent, FromServer, ReconnectPolicy, ToServer};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    type Server = Framed<TcpStream, ServerCodec>;
+
+    /// Accept a connection, complete the handshake and return the id of the
+    /// first subscription
+    async fn accept(listener: &TcpListener) -> (Server, String) {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let (mut server, _) = handshake(tcp, None).await;
+        match server.next().await.unwrap().unwrap().content {
+            ToServer::Subscribe { id, .. } => (server, id),
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    fn endpoint(listener: &TcpListener) -> ConnectOptions {
+        ConnectOptions::new(listener.local_addr().unwrap().to_string())
+    }
+
+    /// Connect and subscribe, while `server` accepts the connection
+    async fn start(
+        failover: Failover,
+        server: impl Future<Output = (Server, String)>,
+    ) -> (Client, crate::Subscription, Server, String) {
+        let client = async {
+            let client = Client::connect_failover(failover, policy()).await.unwrap();
+            let sub = client.subscribe("/a").await.unwrap();
+            (client, sub)
+        };
+        let ((client, sub), (server, id)) = future::join(client, server).await;
+        (client, sub, server, id)
+    }
+
+    fn policy() -> ReconnectPolicy {
+        ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(10))
+            .max_attempts(3)
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_endpoint() {
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backup = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let failover = Failover::new(vec![endpoint(&primary), endpoint(&backup)]);
+        let (client, mut sub, server, id) = start(failover, accept(&primary)).await;
+        assert_eq!(
+            client.active_endpoint(),
+            Some(endpoint(&primary).address().into())
+        );
+
+        // The primary dies mid-session
+        let primary_address = endpoint(&primary).address().to_string();
+        drop(primary);
+        drop(server);
+        let (mut server, resubscribed) = accept(&backup).await;
+        assert_eq!(resubscribed, id);
+        let message = FromServer::Message {
+            destination: "/a".into(),
+            message_id: "1".into(),
+            subscription: id.clone(),
+            ack: None,
+            headers: vec![],
+            body: None,
+        };
+        server.send(message.into()).await.unwrap();
+        assert!(sub.next().await.unwrap().is_ok());
+
+        let endpoints = client.endpoints();
+        assert_eq!(endpoints[0].address, primary_address);
+        assert!(!endpoints[0].active);
+        assert!(endpoints[0].consecutive_failures >= 1);
+        assert!(endpoints[0].last_error.is_some());
+        assert!(endpoints[1].active);
+        assert_eq!(client.active_endpoint(), Some(endpoints[1].address.clone()));
+    }
+
+    #[tokio::test]
+    async fn no_endpoints_is_an_error() {
+        let res = Client::connect_failover(Failover::new(vec![]), policy()).await;
+        assert!(matches!(res, Err(StompError::NoEndpoints)));
+    }
+
+    #[tokio::test]
+    async fn returns_to_primary() {
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary_options = endpoint(&primary);
+        drop(primary);
+        let backup = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let failover = Failover::new(vec![primary_options.clone(), endpoint(&backup)])
+            .return_to_primary(Duration::from_millis(20));
+        let (client, mut sub, mut backup_server, id) = start(failover, accept(&backup)).await;
+        let mut events = client.events();
+        assert_eq!(client.endpoints()[0].consecutive_failures, 1);
+        let send = ToServer::Send {
+            destination: "/b".into(),
+            transaction: None,
+            headers: vec![],
+            body: None,
+        };
+        let receipt = client.send_with_receipt(send).await.unwrap();
+        let sent = backup_server.next().await.unwrap().unwrap();
+        let (_, receipt_id) = sent
+            .extra_headers
+            .into_iter()
+            .find(|(k, _)| k == b"receipt")
+            .unwrap();
+
+        // The primary comes back
+        let primary = TcpListener::bind(primary_options.address()).await.unwrap();
+        let (_primary_server, resubscribed) = accept(&primary).await;
+        assert_eq!(resubscribed, id);
+        let disconnect = match backup_server.next().await.unwrap().unwrap().content {
+            ToServer::Disconnect {
+                receipt: Some(receipt_id),
+            } => receipt_id,
+            other => panic!("Unexpected frame: {:?}", other),
+        };
+        // What was already on its way over the backup connection still arrives
+        let message = FromServer::Message {
+            destination: "/a".into(),
+            message_id: "late".into(),
+            subscription: id.clone(),
+            ack: None,
+            headers: vec![],
+            body: None,
+        };
+        backup_server.send(message.into()).await.unwrap();
+        let receipt_id = String::from_utf8(receipt_id).unwrap();
+        for receipt_id in [receipt_id, disconnect] {
+            let receipt = FromServer::Receipt { receipt_id };
+            backup_server.send(receipt.into()).await.unwrap();
+        }
+        receipt.await.unwrap();
+        match &sub.next().await.unwrap().unwrap().content {
+            FromServer::Message { message_id, .. } => assert_eq!(message_id, "late"),
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+        assert!(backup_server.next().await.is_none());
+        match events.next().await {
+            Some(ConnectionEvent::Reconnected { address }) => {
+                assert_eq!(address, primary_options.address())
+            }
+            other => panic!("Unexpected event: {:?}", other),
+        }
+        assert_eq!(
+            client.active_endpoint().as_deref(),
+            Some(primary_options.address())
+        );
+    }
+}
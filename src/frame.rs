@@ -1,39 +1,112 @@
-use anyhow::{anyhow, bail};
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use std::borrow::Cow;
+use std::ops::Range;
 
-use crate::{AckMode, FromServer, Message, Result, ToServer};
+use crate::{AckMode, FromServer, Message, Result, StompError, ToServer};
+
+/// A parsed header; either side may be owned if it had to be unescaped
+type Header<'a> = (Cow<'a, [u8]>, Cow<'a, [u8]>);
+
+/// A header as passed to `Frame::new`, where `None` values are skipped
+type OptHeader<'a> = (&'a [u8], Option<Cow<'a, [u8]>>);
 
 #[derive(Debug)]
 pub(crate) struct Frame<'a> {
     command: &'a [u8],
     // TODO use ArrayVec to keep headers on the stack
     // (makes this object zero-allocation)
-    headers: Vec<(&'a [u8], Cow<'a, [u8]>)>,
+    headers: Vec<Header<'a>>,
     body: Option<&'a [u8]>,
+    /// The buffer a received frame was parsed from, which the body is sliced
+    /// out of rather than copied
+    buffer: Option<&'a Bytes>,
 }
 
 impl<'a> Frame<'a> {
     pub(crate) fn new(
         command: &'a [u8],
-        headers: &[(&'a [u8], Option<Cow<'a, [u8]>>)],
+        headers: &[OptHeader<'a>],
         body: Option<&'a [u8]>,
     ) -> Frame<'a> {
         let headers = headers
             .iter()
             // filter out headers with None value
-            .filter_map(|&(k, ref v)| v.as_ref().map(|i| (k, (&*i).clone())))
+            .filter_map(|(k, v)| v.as_ref().map(|i| (Cow::Borrowed(*k), i.clone())))
             .collect();
         Frame {
             command,
             headers,
             body,
+            buffer: None,
+        }
+    }
+
+    /// The body as `Bytes`, shared with the receive buffer where possible
+    fn body_bytes(&self) -> Option<Bytes> {
+        self.body.map(|body| match self.buffer {
+            Some(buffer) => buffer.slice_ref(body),
+            None => Bytes::copy_from_slice(body),
+        })
+    }
+
+    /// CONNECT and CONNECTED frames are exempt from header escaping
+    fn is_connect(&self) -> bool {
+        matches!(
+            self.command,
+            b"CONNECT" | b"CONNECTED" | b"STOMP" | b"connect" | b"connected" | b"stomp"
+        )
+    }
+
+    /// Append headers which were not set by the frame content. Headers with a
+    /// name the frame already has are skipped, so the first occurrence wins.
+    pub(crate) fn add_headers(&mut self, headers: &'a [(Vec<u8>, Vec<u8>)]) {
+        for (k, v) in headers {
+            if !self.headers.iter().any(|(key, _)| **key == k[..]) {
+                self.headers
+                    .push((Cow::Borrowed(&k[..]), Cow::Borrowed(&v[..])));
+            }
         }
     }
 
     pub(crate) fn serialize(&self, buffer: &mut BytesMut) {
-        fn write_escaped(b: u8, buffer: &mut BytesMut) {
+        self.serialize_with_eol(buffer, b"\n")
+    }
+
+    /// Serialize, ending the command and header lines with `eol`, which is
+    /// either `\n` or `\r\n`
+    pub(crate) fn serialize_with_eol(&self, buffer: &mut BytesMut, eol: &[u8]) {
+        let requires = self.head_len() + self.body.map(|b| b.len() + 1).unwrap_or(1);
+        if buffer.remaining_mut() < requires {
+            buffer.reserve(requires);
+        }
+        self.serialize_head(buffer, eol);
+        if let Some(body) = self.body {
+            buffer.put_slice(body);
+        }
+        buffer.put_u8(b'\x00');
+    }
+
+    /// Roughly the length of the command and headers once serialized, with
+    /// some room for escapes
+    fn head_len(&self) -> usize {
+        self.command.len()
+            + self
+                .headers
+                .iter()
+                .fold(0, |acc, (k, v)| acc + k.len() + v.len())
+            + 30
+    }
+
+    /// Serialize the command and headers, up to and including the blank line
+    /// before the body, leaving the body and the terminating NUL to the caller
+    pub(crate) fn serialize_head(&self, buffer: &mut BytesMut, eol: &[u8]) {
+        let escape = !self.is_connect();
+        let write_escaped = |b: u8, buffer: &mut BytesMut| {
+            if !escape {
+                buffer.put_u8(b);
+                return;
+            }
             match b {
                 b'\r' => buffer.put_slice(b"\\r"),
                 b'\n' => buffer.put_slice(b"\\n"),
@@ -41,98 +114,367 @@ impl<'a> Frame<'a> {
                 b'\\' => buffer.put_slice(b"\\\\"),
                 b => buffer.put_u8(b),
             }
-        }
+        };
 
-        let requires = self.command.len()
-            + self.body.map(|b| b.len() + 20).unwrap_or(0)
-            + self
-                .headers
-                .iter()
-                .fold(0, |acc, &(ref k, ref v)| acc + k.len() + v.len())
-            + 30;
+        let requires = self.head_len();
         if buffer.remaining_mut() < requires {
             buffer.reserve(requires);
         }
         buffer.put_slice(self.command);
-        buffer.put_u8(b'\n');
-        self.headers.iter().for_each(|&(key, ref val)| {
-            for byte in key {
+        buffer.put_slice(eol);
+        self.headers.iter().for_each(|(key, val)| {
+            for byte in key.iter() {
                 write_escaped(*byte, buffer);
             }
             buffer.put_u8(b':');
             for byte in val.iter() {
                 write_escaped(*byte, buffer);
             }
-            buffer.put_u8(b'\n');
+            buffer.put_slice(eol);
         });
-        if let Some(body) = self.body {
-            //for Text Message in ActiveMQ
-            //buffer.put_slice(&get_content_length_header(&body));
-            buffer.put_u8(b'\n');
-            buffer.put_slice(body);
-        } else {
-            buffer.put_u8(b'\n');
+        buffer.put_slice(eol);
+    }
+}
+
+// Parsing
+
+/// Limits on the size of a received frame, as set on `ClientCodec` and
+/// `ServerCodec`
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    pub(crate) frame_size: usize,
+    pub(crate) headers: usize,
+    pub(crate) header_length: usize,
+    pub(crate) command_length: usize,
+}
+
+impl Limits {
+    /// No limits at all, for the parser tests
+    #[cfg(test)]
+    pub(crate) const NONE: Limits = Limits {
+        frame_size: usize::MAX,
+        headers: usize::MAX,
+        header_length: usize::MAX,
+        command_length: usize::MAX,
+    };
+}
+
+impl Default for Limits {
+    /// The limits ActiveMQ applies to the frames it receives
+    fn default() -> Limits {
+        Limits {
+            frame_size: 100 * 1024 * 1024,
+            headers: 1000,
+            header_length: 10 * 1024,
+            command_length: 1024,
         }
-        buffer.put_u8(b'\x00');
     }
 }
 
-// Nom definitions
+/// The part of a frame the parser is waiting for
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    Command,
+    Headers,
+    /// The body starting at `start`, with its length if content-length was given
+    Body {
+        start: usize,
+        length: Option<usize>,
+    },
+}
 
-named!(eol, preceded!(opt!(tag!("\r")), tag!("\n")));
+/// A resumable frame parser, which keeps its place in the buffer between
+/// calls so that each byte is only looked at once, however many reads a
+/// frame arrives in. Positions are offsets from the start of the frame.
+#[derive(Debug, Clone)]
+pub(crate) struct Parser {
+    pub(crate) limits: Limits,
+    stage: Stage,
+    /// Start of the command or header line being read
+    line: usize,
+    /// How far the buffer has been searched for the end of the line or body
+    scanned: usize,
+    command: Range<usize>,
+    headers: Vec<(Range<usize>, Range<usize>)>,
+    content_length: Option<usize>,
+}
 
-named!(
-    parse_header<(&[u8], Cow<[u8]>)>,
-    pair!(
-        take_until_either!(":\n"),
-        preceded!(
-            tag!(":"),
-            map!(take_until_and_consume1!("\n"), |bytes| Cow::Borrowed(
-                strip_cr(bytes)
-            ))
-        )
-    )
-);
+impl Parser {
+    pub(crate) fn new(limits: Limits) -> Parser {
+        Parser {
+            limits,
+            stage: Stage::Command,
+            line: 0,
+            scanned: 0,
+            command: 0..0,
+            headers: vec![],
+            content_length: None,
+        }
+    }
 
-fn get_content_length(headers: &[(&[u8], Cow<[u8]>)]) -> Option<u32> {
-    for h in headers {
-        if h.0 == b"content-length" {
-            return std::str::from_utf8(&*h.1)
+    /// Whether no part of a frame has been read yet
+    fn is_idle(&self) -> bool {
+        matches!(self.stage, Stage::Command) && self.scanned == 0
+    }
+
+    fn reset(&mut self) {
+        self.stage = Stage::Command;
+        self.line = 0;
+        self.scanned = 0;
+        self.headers.clear();
+        self.content_length = None;
+    }
+
+    /// Continue parsing the frame at the start of `src`, which must still hold
+    /// everything passed to earlier calls. Returns the length of the frame,
+    /// including its terminating NUL, once it is complete.
+    fn parse(&mut self, src: &[u8]) -> Result<Option<usize>> {
+        loop {
+            let (start, length) = match self.stage {
+                Stage::Command | Stage::Headers => {
+                    let end = match find(src, self.scanned, b'\n') {
+                        Some(end) => end,
+                        None => {
+                            self.scanned = src.len();
+                            self.check_frame_size(self.scanned)?;
+                            self.check_line(strip_cr(&src[self.line..]))?;
+                            return Ok(None);
+                        }
+                    };
+                    let line = self.line..self.line + strip_cr(&src[self.line..end]).len();
+                    self.line = end + 1;
+                    self.scanned = end + 1;
+                    self.check_frame_size(self.scanned)?;
+                    self.check_line(&src[line.clone()])?;
+                    self.end_line(src, line)?;
+                    continue;
+                }
+                Stage::Body { start, length } => (start, length),
+            };
+            let end = match length {
+                Some(length) => {
+                    let end = start.saturating_add(length);
+                    if src.len() <= end {
+                        return Ok(None);
+                    }
+                    if src[end] != 0 {
+                        return Err(parse_error(end, "body not followed by NUL"));
+                    }
+                    end
+                }
+                None => match find(src, self.scanned, 0) {
+                    Some(end) => end,
+                    None => {
+                        self.scanned = src.len();
+                        self.check_frame_size(self.scanned)?;
+                        return Ok(None);
+                    }
+                },
+            };
+            self.check_frame_size(end + 1)?;
+            return Ok(Some(end + 1));
+        }
+    }
+
+    /// Check a command or header line, which may not be complete yet
+    fn check_line(&self, line: &[u8]) -> Result<()> {
+        let limits = &self.limits;
+        match self.stage {
+            Stage::Command if line.len() > limits.command_length => {
+                Err(StompError::CommandTooLong(limits.command_length))
+            }
+            Stage::Headers if !line.is_empty() && self.headers.len() >= limits.headers => {
+                Err(StompError::TooManyHeaders(limits.headers))
+            }
+            Stage::Headers if line.len() > limits.header_length => {
+                Err(StompError::HeaderTooLong(limits.header_length))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_frame_size(&self, size: usize) -> Result<()> {
+        if size > self.limits.frame_size {
+            return Err(StompError::FrameTooLarge(self.limits.frame_size));
+        }
+        Ok(())
+    }
+
+    /// Handle a complete command or header line
+    fn end_line(&mut self, src: &[u8], line: Range<usize>) -> Result<()> {
+        if let Stage::Command = self.stage {
+            self.command = line;
+            self.stage = Stage::Headers;
+            return Ok(());
+        }
+        if line.is_empty() {
+            let start = self.scanned;
+            if let Some(length) = self.content_length {
+                // Refuse an oversized body before any of it has arrived
+                self.check_frame_size(start.saturating_add(length).saturating_add(1))?;
+            }
+            self.stage = Stage::Body {
+                start,
+                length: self.content_length,
+            };
+            return Ok(());
+        }
+        let colon = match find(&src[..line.end], line.start, b':') {
+            Some(colon) => colon,
+            None => return Err(parse_error(line.start, "header without ':'")),
+        };
+        let (key, value) = (line.start..colon, colon + 1..line.end);
+        // As with any repeated header, only the first content-length counts
+        let key_bytes = &src[key.clone()];
+        if key_bytes == b"content-length"
+            && !self
+                .headers
+                .iter()
+                .any(|(k, _)| src[k.clone()] == *key_bytes)
+        {
+            let length = std::str::from_utf8(&src[value.clone()])
                 .ok()
-                .and_then(|v| v.parse::<u32>().ok());
+                .and_then(|v| v.parse().ok());
+            match length {
+                Some(length) => self.content_length = Some(length),
+                None => {
+                    return Err(StompError::InvalidHeader {
+                        header: "content-length".into(),
+                        value: String::from_utf8_lossy(&src[value]).into_owned(),
+                    })
+                }
+            }
+        }
+        self.headers.push((key, value));
+        Ok(())
+    }
+
+    /// The frame which `parse` found to take up the first `length` bytes of
+    /// `src`, with header escape sequences decoded as required by STOMP 1.2
+    fn frame<'a>(&self, src: &'a [u8], length: usize) -> Result<Frame<'a>> {
+        let body = match self.stage {
+            Stage::Body {
+                start,
+                length: Some(_),
+            } => Some(&src[start..length - 1]),
+            Stage::Body {
+                start,
+                length: None,
+            } if start < length - 1 => Some(&src[start..length - 1]),
+            _ => None,
+        };
+        let mut frame = Frame {
+            command: &src[self.command.clone()],
+            headers: Vec::with_capacity(self.headers.len()),
+            body,
+            buffer: None,
+        };
+        let escape = !frame.is_connect();
+        for (key, value) in &self.headers {
+            let field = |range: &Range<usize>| {
+                let raw = &src[range.clone()];
+                if !escape {
+                    return Ok(Cow::Borrowed(raw));
+                }
+                unescape(raw).map_err(|at| {
+                    parse_error(range.start + at, "undefined escape sequence in header")
+                })
+            };
+            frame.headers.push((field(key)?, field(value)?));
         }
+        Ok(frame)
     }
-    None
 }
 
-fn is_empty_slice(s: &[u8]) -> Option<&[u8]> {
-    if s.is_empty() {
-        None
-    } else {
-        Some(s)
+/// The position of the first `byte` in `src` at or after `from`
+fn find(src: &[u8], from: usize, byte: u8) -> Option<usize> {
+    src[from..]
+        .iter()
+        .position(|&b| b == byte)
+        .map(|i| from + i)
+}
+
+fn parse_error(offset: usize, reason: &str) -> StompError {
+    StompError::Parse {
+        offset,
+        reason: reason.into(),
     }
 }
 
-named!(
-    pub(crate) parse_frame<Frame>,
-    do_parse!(
-        many0!(eol)
-            >> command: map!(take_until_and_consume!("\n"), strip_cr)
-            >> headers: many0!(parse_header)
-            >> eol
-            >> body: switch!(value!(get_content_length(&*headers)),
-                Some(v) => map!(take!(v), Some) |
-                None => map!(take_until!("\x00"), is_empty_slice)
-            )
-            >> tag!("\x00")
-            >> many0!(complete!(eol))
-            >> (Frame {
-                command,
-                headers,
-                body,
-            })
-    )
-);
+/// Reverse the header escaping applied by `Frame::serialize`.
+/// On failure returns the offset of the undefined escape sequence.
+fn unescape(raw: &[u8]) -> std::result::Result<Cow<'_, [u8]>, usize> {
+    if !raw.contains(&b'\\') {
+        return Ok(Cow::Borrowed(raw));
+    }
+    let mut out = Vec::with_capacity(raw.len());
+    let mut iter = raw.iter().enumerate();
+    while let Some((i, &b)) = iter.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match iter.next() {
+            Some((_, b'r')) => out.push(b'\r'),
+            Some((_, b'n')) => out.push(b'\n'),
+            Some((_, b'c')) => out.push(b':'),
+            Some((_, b'\\')) => out.push(b'\\'),
+            _ => return Err(i),
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// Decode a single frame from the start of `src` using the given conversion,
+/// advancing the buffer past it. Returns `Ok(None)` if more data is needed,
+/// in which case `parser` remembers how far it got for the next call.
+pub(crate) fn decode<T>(
+    src: &mut BytesMut,
+    parser: &mut Parser,
+    convert: impl FnOnce(Frame) -> Result<T>,
+) -> Result<Option<T>> {
+    if parser.is_idle() {
+        // Discard heart-beats so they don't pile up in the buffer on an idle connection
+        let eols = src
+            .iter()
+            .take_while(|&&b| b == b'\n' || b == b'\r')
+            .count();
+        src.advance(eols);
+    }
+    let length = match parser.parse(src) {
+        Ok(Some(length)) => length,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            parser.reset();
+            return Err(e);
+        }
+    };
+    // Split the frame off so that its body can be shared instead of copied
+    let buffer = src.split_to(length).freeze();
+    let frame = parser.frame(&buffer, length);
+    parser.reset();
+    let mut frame = frame?;
+    frame.buffer = Some(&buffer);
+    convert(frame).map(Some)
+}
+
+/// Parse a single, complete frame from the start of `input`, returning the
+/// input which follows it
+#[cfg(test)]
+fn parse_frame(input: &[u8]) -> Result<(&[u8], Frame<'_>)> {
+    let is_eol = |b: &u8| *b == b'\n' || *b == b'\r';
+    let input = &input[input.iter().take_while(|b| is_eol(b)).count()..];
+    let mut parser = Parser::new(Limits::NONE);
+    let length = parser
+        .parse(input)?
+        .ok_or_else(|| parse_error(input.len(), "incomplete frame"))?;
+    let frame = parser.frame(input, length)?;
+    let rest = &input[length..];
+    Ok((
+        &rest[rest.iter().take_while(|b| is_eol(b)).count()..],
+        frame,
+    ))
+}
 
 fn strip_cr(buf: &[u8]) -> &[u8] {
     if let Some(&b'\r') = buf.last() {
@@ -142,34 +484,43 @@ fn strip_cr(buf: &[u8]) -> &[u8] {
     }
 }
 
-fn fetch_header<'a>(headers: &'a [(&'a [u8], Cow<'a, [u8]>)], key: &'a str) -> Option<String> {
+/// The error for a header whose key or value is not UTF-8, as STOMP requires
+fn not_utf8(k: &[u8], v: &[u8]) -> StompError {
+    StompError::InvalidHeader {
+        header: String::from_utf8_lossy(k).into(),
+        value: String::from_utf8_lossy(v).into(),
+    }
+}
+
+fn fetch_header<'a>(headers: &'a [Header<'a>], key: &'a str) -> Result<Option<String>> {
     let kk = key.as_bytes();
-    for &(k, ref v) in headers {
-        if &*k == kk {
-            return String::from_utf8(v.to_vec()).ok();
+    for (k, v) in headers {
+        if **k == *kk {
+            return String::from_utf8(v.to_vec())
+                .map(Some)
+                .map_err(|_| not_utf8(k, v));
         }
     }
-    None
+    Ok(None)
 }
 
-fn all_headers<'a>(headers: &'a [(&'a [u8], Cow<'a, [u8]>)]) -> Vec<(String, String)> {
+fn all_headers<'a>(headers: &'a [Header<'a>]) -> Result<Vec<(String, String)>> {
     let mut res = Vec::new();
-    for &(k, ref v) in headers {
+    for (k, v) in headers {
         let entry = (
-            String::from_utf8(k.to_vec()).unwrap(),
-            String::from_utf8(v.to_vec()).unwrap(),
+            String::from_utf8(k.to_vec()).map_err(|_| not_utf8(k, v))?,
+            String::from_utf8(v.to_vec()).map_err(|_| not_utf8(k, v))?,
         );
         res.push(entry);
     }
-    res
+    Ok(res)
 }
 
-fn expect_header<'a>(headers: &'a [(&'a [u8], Cow<'a, [u8]>)], key: &'a str) -> Result<String> {
-    fetch_header(headers, key).ok_or_else(|| anyhow!("Expected header '{}' missing", key))
+fn expect_header<'a>(headers: &'a [Header<'a>], key: &'a str) -> Result<String> {
+    fetch_header(headers, key)?.ok_or_else(|| StompError::MissingHeader(key.into()))
 }
 
 impl<'a> Frame<'a> {
-    #[allow(dead_code)]
     pub(crate) fn to_client_msg(&'a self) -> Result<Message<ToServer>> {
         use self::expect_header as eh;
         use self::fetch_header as fh;
@@ -185,7 +536,7 @@ impl<'a> Frame<'a> {
                     b"passcode",
                     b"heart-beat",
                 ];
-                let heartbeat = if let Some(hb) = fh(h, "heart-beat") {
+                let heartbeat = if let Some(hb) = fh(h, "heart-beat")? {
                     Some(parse_heartbeat(&hb)?)
                 } else {
                     None
@@ -193,24 +544,25 @@ impl<'a> Frame<'a> {
                 Connect {
                     accept_version: eh(h, "accept-version")?,
                     host: eh(h, "host")?,
-                    login: fh(h, "login"),
-                    passcode: fh(h, "passcode"),
+                    login: fh(h, "login")?,
+                    passcode: fh(h, "passcode")?,
                     heartbeat,
+                    stomp: self.command.eq_ignore_ascii_case(b"STOMP"),
                 }
             }
             b"DISCONNECT" | b"disconnect" => {
                 expect_keys = &[b"receipt"];
                 Disconnect {
-                    receipt: fh(h, "receipt"),
+                    receipt: fh(h, "receipt")?,
                 }
             }
             b"SEND" | b"send" => {
                 expect_keys = &[b"destination", b"transaction"];
                 Send {
                     destination: eh(h, "destination")?,
-                    transaction: fh(h, "transaction"),
+                    transaction: fh(h, "transaction")?,
                     headers: vec![],
-                    body: self.body.map(|v| v.to_vec()),
+                    body: self.body_bytes(),
                 }
             }
             b"SUBSCRIBE" | b"subscribe" => {
@@ -218,11 +570,16 @@ impl<'a> Frame<'a> {
                 Subscribe {
                     destination: eh(h, "destination")?,
                     id: eh(h, "id")?,
-                    ack: match fh(h, "ack").as_ref().map(|s| s.as_str()) {
+                    ack: match fh(h, "ack")?.as_deref() {
                         Some("auto") => Some(AckMode::Auto),
                         Some("client") => Some(AckMode::Client),
                         Some("client-individual") => Some(AckMode::ClientIndividual),
-                        Some(other) => bail!("Invalid ack mode: {}", other),
+                        Some(other) => {
+                            return Err(StompError::InvalidHeader {
+                                header: "ack".into(),
+                                value: other.into(),
+                            })
+                        }
                         None => None,
                     },
                 }
@@ -235,14 +592,14 @@ impl<'a> Frame<'a> {
                 expect_keys = &[b"id", b"transaction"];
                 Ack {
                     id: eh(h, "id")?,
-                    transaction: fh(h, "transaction"),
+                    transaction: fh(h, "transaction")?,
                 }
             }
             b"NACK" | b"nack" => {
                 expect_keys = &[b"id", b"transaction"];
                 Nack {
                     id: eh(h, "id")?,
-                    transaction: fh(h, "transaction"),
+                    transaction: fh(h, "transaction")?,
                 }
             }
             b"BEGIN" | b"begin" => {
@@ -263,13 +620,17 @@ impl<'a> Frame<'a> {
                     transaction: eh(h, "transaction")?,
                 }
             }
-            other => bail!("Frame not recognized: {:?}", String::from_utf8_lossy(other)),
+            other => {
+                return Err(StompError::UnknownCommand(
+                    String::from_utf8_lossy(other).into(),
+                ))
+            }
         };
         let extra_headers = h
             .iter()
-            .filter_map(|&(k, ref v)| {
-                if !expect_keys.contains(&k) {
-                    Some((k.to_vec(), (&*v).to_vec()))
+            .filter_map(|(k, v)| {
+                if !expect_keys.contains(&&**k) {
+                    Some((k.to_vec(), v.to_vec()))
                 } else {
                     None
                 }
@@ -290,21 +651,27 @@ impl<'a> Frame<'a> {
         let content = match self.command {
             b"CONNECTED" | b"connected" => {
                 expect_keys = &[b"version", b"session", b"server", b"heart-beat"];
+                let heartbeat = if let Some(hb) = fh(h, "heart-beat")? {
+                    Some(parse_heartbeat(&hb)?)
+                } else {
+                    None
+                };
                 Connected {
                     version: eh(h, "version")?,
-                    session: fh(h, "session"),
-                    server: fh(h, "server"),
-                    heartbeat: fh(h, "heart-beat"),
+                    session: fh(h, "session")?,
+                    server: fh(h, "server")?,
+                    heartbeat,
                 }
             }
             b"MESSAGE" | b"message" => {
-                expect_keys = &[b"destination", b"message-id", b"subscription"];
+                expect_keys = &[b"destination", b"message-id", b"subscription", b"ack"];
                 Msg {
                     destination: eh(h, "destination")?,
                     message_id: eh(h, "message-id")?,
                     subscription: eh(h, "subscription")?,
-                    headers: all_headers(h),
-                    body: self.body.map(|v| v.to_vec()),
+                    ack: fh(h, "ack")?,
+                    headers: all_headers(h)?,
+                    body: self.body_bytes(),
                 }
             }
             b"RECEIPT" | b"receipt" => {
@@ -316,17 +683,21 @@ impl<'a> Frame<'a> {
             b"ERROR" | b"error" => {
                 expect_keys = &[b"message"];
                 Error {
-                    message: fh(h, "message"),
+                    message: fh(h, "message")?,
                     body: self.body.map(|v| v.to_vec()),
                 }
             }
-            other => bail!("Frame not recognized: {:?}", String::from_utf8_lossy(other)),
+            other => {
+                return Err(StompError::UnknownCommand(
+                    String::from_utf8_lossy(other).into(),
+                ))
+            }
         };
         let extra_headers = h
             .iter()
-            .filter_map(|&(k, ref v)| {
-                if !expect_keys.contains(&k) {
-                    Some((k.to_vec(), (&*v).to_vec()))
+            .filter_map(|(k, v)| {
+                if !expect_keys.contains(&&**k) {
+                    Some((k.to_vec(), v.to_vec()))
                 } else {
                     None
                 }
@@ -342,16 +713,23 @@ impl<'a> Frame<'a> {
 fn opt_str_to_bytes<'a>(s: &'a Option<String>) -> Option<Cow<'a, [u8]>> {
     s.as_ref().map(|v| Cow::Borrowed(v.as_bytes()))
 }
-#[allow(dead_code)]
-fn get_content_length_header(body: &[u8]) -> Vec<u8> {
-    format!("content-length:{}\n", body.len()).into()
+
+fn content_length<'a>(body: Option<&[u8]>) -> Option<Cow<'a, [u8]>> {
+    body.map(|b| Cow::Owned(b.len().to_string().into_bytes()))
 }
 
-fn parse_heartbeat(hb: &str) -> Result<(u32, u32)> {
+pub(crate) fn parse_heartbeat(hb: &str) -> Result<(u32, u32)> {
+    let invalid = || StompError::InvalidHeader {
+        header: "heart-beat".into(),
+        value: hb.into(),
+    };
     let mut split = hb.splitn(2, ',');
-    let left = split.next().ok_or_else(|| anyhow!("Bad heartbeat"))?;
-    let right = split.next().ok_or_else(|| anyhow!("Bad heartbeat"))?;
-    Ok((left.parse()?, right.parse()?))
+    let left = split.next().ok_or_else(invalid)?;
+    let right = split.next().ok_or_else(invalid)?;
+    Ok((
+        left.trim().parse().map_err(|_| invalid())?,
+        right.trim().parse().map_err(|_| invalid())?,
+    ))
 }
 
 impl ToServer {
@@ -366,8 +744,9 @@ impl ToServer {
                 ref login,
                 ref passcode,
                 ref heartbeat,
+                stomp,
             } => Frame::new(
-                b"CONNECT",
+                if stomp { b"STOMP" } else { b"CONNECT" },
                 &[
                     (b"accept-version", Some(Borrowed(accept_version.as_bytes()))),
                     (b"host", Some(Borrowed(host.as_bytes()))),
@@ -418,14 +797,24 @@ impl ToServer {
                 ref headers,
                 ref body,
             } => {
-                let mut hdr: Vec<(&[u8], Option<Cow<[u8]>>)> = vec![
+                // ActiveMQ takes a SEND with content-length for a bytes message,
+                // so it is only added when the body cannot be framed without it
+                let has_nul = body.as_ref().is_some_and(|b| b.contains(&0));
+                let mut hdr: Vec<OptHeader> = vec![
                     (b"destination", Some(Borrowed(destination.as_bytes()))),
-                    (b"id", sb(transaction)),
+                    (b"transaction", sb(transaction)),
+                    (
+                        b"content-length",
+                        content_length(body.as_deref()).filter(|_| has_nul),
+                    ),
                 ];
                 for (key, val) in headers {
-                    hdr.push((key.as_bytes(), Some(Borrowed(val.as_bytes()))));
+                    // As for extra headers, the first occurrence wins
+                    if !hdr.iter().any(|(k, v)| v.is_some() && *k == key.as_bytes()) {
+                        hdr.push((key.as_bytes(), Some(Borrowed(val.as_bytes()))));
+                    }
                 }
-                Frame::new(b"SEND", &hdr, body.as_ref().map(|v| v.as_ref()))
+                Frame::new(b"SEND", &hdr, body.as_deref())
             }
 
             Ack {
@@ -473,9 +862,84 @@ impl ToServer {
     }
 }
 
+impl FromServer {
+    pub(crate) fn to_frame<'a>(&'a self) -> Frame<'a> {
+        use self::opt_str_to_bytes as sb;
+        use Cow::*;
+        use FromServer::*;
+        match *self {
+            Connected {
+                ref version,
+                ref session,
+                ref server,
+                ref heartbeat,
+            } => Frame::new(
+                b"CONNECTED",
+                &[
+                    (b"version", Some(Borrowed(version.as_bytes()))),
+                    (b"session", sb(session)),
+                    (b"server", sb(server)),
+                    (
+                        b"heart-beat",
+                        heartbeat.map(|(v1, v2)| Owned(format!("{},{}", v1, v2).into())),
+                    ),
+                ],
+                None,
+            ),
+
+            Message {
+                ref destination,
+                ref message_id,
+                ref subscription,
+                ref ack,
+                ref headers,
+                ref body,
+            } => {
+                let mut hdr: Vec<OptHeader> = vec![
+                    (b"destination", Some(Borrowed(destination.as_bytes()))),
+                    (b"message-id", Some(Borrowed(message_id.as_bytes()))),
+                    (b"subscription", Some(Borrowed(subscription.as_bytes()))),
+                    (b"ack", sb(ack)),
+                ];
+                // `headers` also holds the standard headers of a parsed frame
+                for (key, val) in headers {
+                    if !matches!(
+                        key.as_str(),
+                        "destination" | "message-id" | "subscription" | "ack" | "content-length"
+                    ) {
+                        hdr.push((key.as_bytes(), Some(Borrowed(val.as_bytes()))));
+                    }
+                }
+                hdr.push((b"content-length", content_length(body.as_deref())));
+                Frame::new(b"MESSAGE", &hdr, body.as_deref())
+            }
+
+            Receipt { ref receipt_id } => Frame::new(
+                b"RECEIPT",
+                &[(b"receipt-id", Some(Borrowed(receipt_id.as_bytes())))],
+                None,
+            ),
+
+            Error {
+                ref message,
+                ref body,
+            } => Frame::new(
+                b"ERROR",
+                &[
+                    (b"message", sb(message)),
+                    (b"content-length", content_length(body.as_deref())),
+                ],
+                body.as_deref(),
+            ),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client::ClientCodec;
+    use tokio_util::codec::Encoder;
 
     #[test]
     fn parse_and_serialize_connect() {
@@ -492,7 +956,7 @@ login:user\npasscode:password\n\n\x00"
             (b"login", b"user"),
             (b"passcode", b"password"),
         ];
-        let fh: Vec<_> = frame.headers.iter().map(|&(k, ref v)| (k, &**v)).collect();
+        let fh: Vec<_> = frame.headers.iter().map(|(k, v)| (&**k, &**v)).collect();
         assert_eq!(fh, headers_expect);
         assert_eq!(frame.body, None);
         let stomp = frame.to_client_msg().unwrap();
@@ -520,12 +984,636 @@ subscription:some-id
             (b"subscription", b"some-id"),
             (b"content-length", b"50"),
         ];
-        let fh: Vec<_> = frame.headers.iter().map(|&(k, ref v)| (k, &**v)).collect();
+        let fh: Vec<_> = frame.headers.iter().map(|(k, v)| (&**k, &**v)).collect();
         assert_eq!(fh, headers_expect);
         assert_eq!(frame.body, Some(body.as_bytes()));
-        frame.to_server_msg().unwrap();
-        // TODO to_frame for FromServer
-        // let roundtrip = stomp.to_frame().serialize();
-        // assert_eq!(roundtrip, data);
+        let stomp = frame.to_server_msg().unwrap();
+        let mut buffer = BytesMut::new();
+        stomp.to_frame().serialize(&mut buffer);
+        assert_eq!(&*buffer, &data[1..]);
+    }
+
+    #[test]
+    fn parse_unescapes_headers() {
+        let data = b"MESSAGE
+destination:/queue/a\\cb
+message-id:line\\none\\rtwo
+subscription:back\\\\slash
+we\\cird:key\n\n\x00"
+            .to_vec();
+        let (_, frame) = parse_frame(&data).unwrap();
+        let headers_expect: Vec<(&[u8], &[u8])> = vec![
+            (&b"destination"[..], &b"/queue/a:b"[..]),
+            (b"message-id", b"line\none\rtwo"),
+            (b"subscription", b"back\\slash"),
+            (b"we:ird", b"key"),
+        ];
+        let fh: Vec<_> = frame.headers.iter().map(|(k, v)| (&**k, &**v)).collect();
+        assert_eq!(fh, headers_expect);
+        match frame.to_server_msg().unwrap().content {
+            FromServer::Message { destination, .. } => assert_eq!(destination, "/queue/a:b"),
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn escaped_headers_roundtrip() {
+        for value in &["a:b", "a\nb", "a\rb", "a\\b", ":\r\n\\:"] {
+            let msg: Message<ToServer> = ToServer::Send {
+                destination: value.to_string(),
+                transaction: None,
+                headers: vec![(value.to_string(), value.to_string())],
+                body: None,
+            }
+            .into();
+            let mut buffer = BytesMut::new();
+            msg.to_frame().serialize(&mut buffer);
+            let (_, frame) = parse_frame(&buffer).unwrap();
+            let parsed = frame.to_client_msg().unwrap();
+            match parsed.content {
+                ToServer::Send { destination, .. } => assert_eq!(destination, *value),
+                other => panic!("Unexpected frame: {:?}", other),
+            }
+            let extra = (value.as_bytes().to_vec(), value.as_bytes().to_vec());
+            assert_eq!(parsed.extra_headers, vec![extra]);
+        }
+    }
+
+    #[test]
+    fn undefined_escape_is_rejected() {
+        let data = b"MESSAGE
+destination:tab\\there
+message-id:1
+subscription:1\n\n\x00"
+            .to_vec();
+        match parse_frame(&data) {
+            Err(StompError::Parse { offset, reason }) => {
+                assert!(data[offset..].starts_with(b"\\there"));
+                assert_eq!(reason, "undefined escape sequence in header");
+            }
+            other => panic!("Expected escape failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connect_headers_are_not_unescaped() {
+        let data = b"CONNECTED
+version:1.2
+server:weird\\cname\n\n\x00"
+            .to_vec();
+        let (_, frame) = parse_frame(&data).unwrap();
+        match frame.to_server_msg().unwrap().content {
+            FromServer::Connected { server, .. } => {
+                assert_eq!(server.as_deref(), Some("weird\\cname"))
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+        let msg: Message<ToServer> = ToServer::Connect {
+            accept_version: "1.2".into(),
+            host: "vhost:with:colons".into(),
+            login: None,
+            passcode: None,
+            heartbeat: None,
+            stomp: false,
+        }
+        .into();
+        let mut buffer = BytesMut::new();
+        msg.to_frame().serialize(&mut buffer);
+        assert_eq!(
+            &*buffer,
+            &b"CONNECT\naccept-version:1.2\nhost:vhost:with:colons\n\n\x00"[..]
+        );
+    }
+
+    #[test]
+    fn decode_resumes_across_partial_reads() {
+        let wire = b"\n\nMESSAGE\r\ndestination:/q\r\nmessage-id:a\\cb\r\nsubscription:s\r\n\
+                     content-length:3\r\n\r\n\x00\n\x00\x00\n\
+                     RECEIPT\nreceipt-id:r-1\n\n\x00\
+                     ERROR\nmessage:bad\n\nno length\x00\n";
+        let decode_all = |parser: &mut Parser, buffer: &mut BytesMut, out: &mut Vec<String>| {
+            while let Some(msg) = decode(buffer, parser, |f| f.to_server_msg()).unwrap() {
+                out.push(format!("{:?}", msg));
+            }
+        };
+        let mut whole = vec![];
+        let mut parser = Parser::new(Limits::NONE);
+        decode_all(&mut parser, &mut BytesMut::from(&wire[..]), &mut whole);
+        assert_eq!(whole.len(), 3);
+
+        let mut bytewise = vec![];
+        let mut parser = Parser::new(Limits::NONE);
+        let mut buffer = BytesMut::new();
+        for byte in wire.iter() {
+            buffer.extend_from_slice(&[*byte]);
+            decode_all(&mut parser, &mut buffer, &mut bytewise);
+        }
+        assert_eq!(bytewise, whole);
+        assert!(parser.is_idle());
+    }
+
+    #[test]
+    fn decoded_body_shares_the_read_buffer() {
+        let mut buffer = BytesMut::from(
+            &b"MESSAGE\ndestination:/q\nmessage-id:1\nsubscription:s\n\nshared\x00"[..],
+        );
+        let read = buffer.as_ptr_range();
+        let msg = decode(&mut buffer, &mut Parser::new(Limits::NONE), |f| {
+            f.to_server_msg()
+        })
+        .unwrap()
+        .unwrap();
+        match msg.content {
+            FromServer::Message { body, .. } => {
+                let body = body.unwrap();
+                assert_eq!(body, "shared");
+                assert!(read.contains(&body.as_ptr()));
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn non_utf8_headers_are_invalid() {
+        let cases: &[(&[u8], &str)] = &[
+            (
+                b"MESSAGE\ndestination:/q\nmessage-id:1\nsubscription:s\nx-\xff:1\n\n\x00",
+                "x-\u{fffd}",
+            ),
+            (
+                b"MESSAGE\ndestination:/q\nmessage-id:1\nsubscription:s\nx-key:\xff\n\n\x00",
+                "x-key",
+            ),
+            (
+                b"MESSAGE\ndestination:/q\xfe\nmessage-id:1\nsubscription:s\n\n\x00",
+                "destination",
+            ),
+            (b"ERROR\nmessage:\xc3\n\n\x00", "message"),
+        ];
+        for (data, expected) in cases {
+            let mut buffer = BytesMut::from(&data[..]);
+            match decode(&mut buffer, &mut Parser::new(Limits::NONE), |f| {
+                f.to_server_msg()
+            }) {
+                Err(StompError::InvalidHeader { header, .. }) => assert_eq!(header, *expected),
+                other => panic!("Unexpected: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_errors_are_typed() {
+        let decode = |data: &[u8]| {
+            let mut buffer = BytesMut::from(data);
+            decode(&mut buffer, &mut Parser::new(Limits::NONE), |f| {
+                f.to_server_msg()
+            })
+        };
+        match decode(b"MESSAGE\ndestination:a\\tb\n\n\x00") {
+            Err(StompError::Parse { offset, .. }) => assert_eq!(offset, 21),
+            other => panic!("Unexpected: {:?}", other),
+        }
+        match decode(b"MESSAGE\ndestination:a\nsubscription:1\n\n\x00") {
+            Err(StompError::MissingHeader(header)) => assert_eq!(header, "message-id"),
+            other => panic!("Unexpected: {:?}", other),
+        }
+        match decode(b"BOGUS\n\n\x00") {
+            Err(StompError::UnknownCommand(command)) => assert_eq!(command, "BOGUS"),
+            other => panic!("Unexpected: {:?}", other),
+        }
+        match decode(b"CONNECTED\nversion:1.2\nheart-beat:soon\n\n\x00") {
+            Err(StompError::InvalidHeader { header, value }) => {
+                assert_eq!((&*header, &*value), ("heart-beat", "soon"))
+            }
+            other => panic!("Unexpected: {:?}", other),
+        }
+        match decode(b"SEND\ndestination:/q\ncontent-length:3x\n\nabc\x00") {
+            Err(StompError::InvalidHeader { header, value }) => {
+                assert_eq!((&*header, &*value), ("content-length", "3x"))
+            }
+            other => panic!("Unexpected: {:?}", other),
+        }
+        match decode(b"CONNECTED\nversion:1.2\nheart-beat:0, 500\n\n\x00") {
+            Ok(Some(msg)) => match msg.content {
+                FromServer::Connected { heartbeat, .. } => assert_eq!(heartbeat, Some((0, 500))),
+                other => panic!("Unexpected: {:?}", other),
+            },
+            other => panic!("Unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn extra_headers_are_written_for_every_client_frame() {
+        /// The content, its extra headers and the expected frame
+        type Case<'a> = (ToServer, &'a [(&'a str, &'a str)], &'a str);
+        let cases: Vec<Case> = vec![
+            (
+                ToServer::Connect {
+                    accept_version: "1.2".into(),
+                    host: "vh".into(),
+                    login: None,
+                    passcode: None,
+                    heartbeat: None,
+                    stomp: false,
+                },
+                &[("client-id", "c1"), ("host", "other")],
+                "CONNECT\naccept-version:1.2\nhost:vh\nclient-id:c1\n\n\x00",
+            ),
+            (
+                ToServer::Disconnect {
+                    receipt: Some("r1".into()),
+                },
+                &[("receipt", "r2"), ("x-reason", "done")],
+                "DISCONNECT\nreceipt:r1\nx-reason:done\n\n\x00",
+            ),
+            (
+                ToServer::Subscribe {
+                    destination: "/q".into(),
+                    id: "s1".into(),
+                    ack: None,
+                },
+                &[("selector", "colour = 'red'"), ("id", "s2")],
+                "SUBSCRIBE\ndestination:/q\nid:s1\nselector:colour = 'red'\n\n\x00",
+            ),
+            (
+                ToServer::Unsubscribe { id: "s1".into() },
+                &[("durable-subscription-name", "d")],
+                "UNSUBSCRIBE\nid:s1\ndurable-subscription-name:d\n\n\x00",
+            ),
+            (
+                ToServer::Send {
+                    destination: "/q".into(),
+                    transaction: None,
+                    headers: vec![
+                        ("persistent".into(), "true".into()),
+                        ("destination".into(), "/elsewhere".into()),
+                    ],
+                    body: None,
+                },
+                &[("persistent", "false"), ("priority", "9")],
+                "SEND\ndestination:/q\npersistent:true\npriority:9\n\n\x00",
+            ),
+            (
+                ToServer::Ack {
+                    id: "a1".into(),
+                    transaction: None,
+                },
+                &[("transaction", "tx-1")],
+                "ACK\nid:a1\ntransaction:tx-1\n\n\x00",
+            ),
+            (
+                ToServer::Nack {
+                    id: "a1".into(),
+                    transaction: None,
+                },
+                &[("requeue", "false"), ("requeue", "true")],
+                "NACK\nid:a1\nrequeue:false\n\n\x00",
+            ),
+            (
+                ToServer::Begin {
+                    transaction: "tx-1".into(),
+                },
+                &[("x-timeout", "10")],
+                "BEGIN\ntransaction:tx-1\nx-timeout:10\n\n\x00",
+            ),
+            (
+                ToServer::Commit {
+                    transaction: "tx-1".into(),
+                },
+                &[("receipt", "r1")],
+                "COMMIT\ntransaction:tx-1\nreceipt:r1\n\n\x00",
+            ),
+            (
+                ToServer::Abort {
+                    transaction: "tx-1".into(),
+                },
+                &[("transaction", "tx-2"), ("receipt", "r:1")],
+                "ABORT\ntransaction:tx-1\nreceipt:r\\c1\n\n\x00",
+            ),
+        ];
+        for (content, extra, expected) in cases {
+            let msg = Message {
+                content,
+                extra_headers: extra
+                    .iter()
+                    .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                    .collect(),
+            };
+            let mut buffer = BytesMut::new();
+            msg.to_frame().serialize(&mut buffer);
+            assert_eq!(String::from_utf8_lossy(&buffer), expected);
+        }
+    }
+
+    /// Check a client frame against its exact form on the wire, both when
+    /// serializing it and when parsing the wire form
+    fn golden_client(content: ToServer, wire: &[u8]) {
+        golden_client_with_eol(content, wire, b"\n")
+    }
+
+    /// As `golden_client`, with lines ending in `eol`
+    fn golden_client_with_eol(content: ToServer, wire: &[u8], eol: &[u8]) {
+        let msg = Message::from(content);
+        let mut buffer = BytesMut::new();
+        msg.to_frame().serialize_with_eol(&mut buffer, eol);
+        assert_eq!(&buffer[..], wire, "serializing {:?}", msg.content);
+
+        let (rest, frame) = parse_frame(wire).unwrap();
+        assert!(rest.is_empty());
+        let parsed = frame.to_client_msg().unwrap();
+        let mut buffer = BytesMut::new();
+        parsed.to_frame().serialize_with_eol(&mut buffer, eol);
+        assert_eq!(&buffer[..], wire, "re-serializing {:?}", parsed);
+        // Custom SEND headers are parsed as extra headers, checked above
+        let without_headers = |mut content: ToServer| {
+            if let ToServer::Send { headers, .. } = &mut content {
+                headers.clear();
+            }
+            format!("{:?}", content)
+        };
+        assert_eq!(
+            without_headers(parsed.content),
+            without_headers(msg.content)
+        );
+    }
+
+    /// As `golden_client`, for a server frame
+    fn golden_server(content: FromServer, wire: &[u8]) {
+        golden_server_with_eol(content, wire, b"\n")
+    }
+
+    /// As `golden_server`, with lines ending in `eol`
+    fn golden_server_with_eol(content: FromServer, wire: &[u8], eol: &[u8]) {
+        let msg = Message::from(content);
+        let mut buffer = BytesMut::new();
+        msg.to_frame().serialize_with_eol(&mut buffer, eol);
+        assert_eq!(&buffer[..], wire, "serializing {:?}", msg.content);
+
+        let (rest, frame) = parse_frame(wire).unwrap();
+        assert!(rest.is_empty());
+        let parsed = frame.to_server_msg().unwrap();
+        let mut buffer = BytesMut::new();
+        parsed.to_frame().serialize_with_eol(&mut buffer, eol);
+        assert_eq!(&buffer[..], wire, "re-serializing {:?}", parsed);
+        // MESSAGE headers hold every header of the parsed frame, checked above
+        let without_headers = |mut content: FromServer| {
+            if let FromServer::Message { headers, .. } = &mut content {
+                headers.clear();
+            }
+            format!("{:?}", content)
+        };
+        assert_eq!(
+            without_headers(parsed.content),
+            without_headers(msg.content)
+        );
+    }
+
+    #[test]
+    fn golden_client_frames() {
+        golden_client(
+            ToServer::Connect {
+                accept_version: "1.1,1.2".into(),
+                host: "vh".into(),
+                login: Some("guest".into()),
+                passcode: Some("p:w".into()),
+                heartbeat: Some((1000, 2000)),
+                stomp: false,
+            },
+            b"CONNECT\naccept-version:1.1,1.2\nhost:vh\nlogin:guest\npasscode:p:w\n\
+              heart-beat:1000,2000\n\n\x00",
+        );
+        golden_client(
+            ToServer::Connect {
+                accept_version: "1.2".into(),
+                host: "/".into(),
+                login: None,
+                passcode: None,
+                heartbeat: None,
+                stomp: true,
+            },
+            b"STOMP\naccept-version:1.2\nhost:/\n\n\x00",
+        );
+        golden_client(
+            ToServer::Disconnect { receipt: None },
+            b"DISCONNECT\n\n\x00",
+        );
+        golden_client(
+            ToServer::Disconnect {
+                receipt: Some("bye".into()),
+            },
+            b"DISCONNECT\nreceipt:bye\n\n\x00",
+        );
+        golden_client(
+            ToServer::Subscribe {
+                destination: "/queue/a:b".into(),
+                id: "sub-0".into(),
+                ack: None,
+            },
+            b"SUBSCRIBE\ndestination:/queue/a\\cb\nid:sub-0\n\n\x00",
+        );
+        for (ack, name) in [
+            (AckMode::Auto, "auto"),
+            (AckMode::Client, "client"),
+            (AckMode::ClientIndividual, "client-individual"),
+        ] {
+            golden_client(
+                ToServer::Subscribe {
+                    destination: "/q".into(),
+                    id: "1".into(),
+                    ack: Some(ack),
+                },
+                format!("SUBSCRIBE\ndestination:/q\nid:1\nack:{}\n\n\x00", name).as_bytes(),
+            );
+        }
+        golden_client(
+            ToServer::Unsubscribe { id: "sub-0".into() },
+            b"UNSUBSCRIBE\nid:sub-0\n\n\x00",
+        );
+        golden_client(
+            ToServer::Send {
+                destination: "/q".into(),
+                transaction: Some("tx-1".into()),
+                headers: vec![("content-type".into(), "text/plain".into())],
+                body: Some(Bytes::from_static(b"hello\r\nworld")),
+            },
+            b"SEND\ndestination:/q\ntransaction:tx-1\ncontent-type:text/plain\n\n\
+              hello\r\nworld\x00",
+        );
+        golden_client(
+            ToServer::Send {
+                destination: "/q".into(),
+                transaction: None,
+                headers: vec![("key:1".into(), "line\nbreak\\".into())],
+                body: Some(Bytes::from_static(b"a\x00b")),
+            },
+            b"SEND\ndestination:/q\ncontent-length:3\nkey\\c1:line\\nbreak\\\\\n\na\x00b\x00",
+        );
+        golden_client(
+            ToServer::Send {
+                destination: "/q".into(),
+                transaction: None,
+                headers: vec![],
+                body: None,
+            },
+            b"SEND\ndestination:/q\n\n\x00",
+        );
+        golden_client(
+            ToServer::Ack {
+                id: "a-1".into(),
+                transaction: Some("tx-1".into()),
+            },
+            b"ACK\nid:a-1\ntransaction:tx-1\n\n\x00",
+        );
+        golden_client(
+            ToServer::Ack {
+                id: "a-1".into(),
+                transaction: None,
+            },
+            b"ACK\nid:a-1\n\n\x00",
+        );
+        golden_client(
+            ToServer::Nack {
+                id: "a-2".into(),
+                transaction: None,
+            },
+            b"NACK\nid:a-2\n\n\x00",
+        );
+        golden_client(
+            ToServer::Begin {
+                transaction: "tx-1".into(),
+            },
+            b"BEGIN\ntransaction:tx-1\n\n\x00",
+        );
+        golden_client(
+            ToServer::Commit {
+                transaction: "tx-1".into(),
+            },
+            b"COMMIT\ntransaction:tx-1\n\n\x00",
+        );
+        golden_client(
+            ToServer::Abort {
+                transaction: "tx-1".into(),
+            },
+            b"ABORT\ntransaction:tx-1\n\n\x00",
+        );
+    }
+
+    #[test]
+    fn golden_server_frames() {
+        golden_server(
+            FromServer::Connected {
+                version: "1.2".into(),
+                session: Some("s-1".into()),
+                server: Some("test/0.1".into()),
+                heartbeat: Some((0, 1000)),
+            },
+            b"CONNECTED\nversion:1.2\nsession:s-1\nserver:test/0.1\nheart-beat:0,1000\n\n\x00",
+        );
+        golden_server(
+            FromServer::Connected {
+                version: "1.2".into(),
+                session: None,
+                server: None,
+                heartbeat: None,
+            },
+            b"CONNECTED\nversion:1.2\n\n\x00",
+        );
+        golden_server(
+            FromServer::Message {
+                destination: "/q".into(),
+                message_id: "m:1".into(),
+                subscription: "sub-0".into(),
+                ack: Some("a-1".into()),
+                headers: vec![("x-key".into(), "a\nb".into())],
+                body: Some(Bytes::from_static(b"ab\x00\r\n")),
+            },
+            b"MESSAGE\ndestination:/q\nmessage-id:m\\c1\nsubscription:sub-0\nack:a-1\n\
+              x-key:a\\nb\ncontent-length:5\n\nab\x00\r\n\x00",
+        );
+        golden_server(
+            FromServer::Message {
+                destination: "/q".into(),
+                message_id: "1".into(),
+                subscription: "s".into(),
+                ack: None,
+                headers: vec![],
+                body: None,
+            },
+            b"MESSAGE\ndestination:/q\nmessage-id:1\nsubscription:s\n\n\x00",
+        );
+        golden_server(
+            FromServer::Receipt {
+                receipt_id: "r-1".into(),
+            },
+            b"RECEIPT\nreceipt-id:r-1\n\n\x00",
+        );
+        golden_server(
+            FromServer::Error {
+                message: Some("bad:thing".into()),
+                body: Some(b"details".to_vec()),
+            },
+            b"ERROR\nmessage:bad\\cthing\ncontent-length:7\n\ndetails\x00",
+        );
+        golden_server(
+            FromServer::Error {
+                message: None,
+                body: None,
+            },
+            b"ERROR\n\n\x00",
+        );
+    }
+
+    #[test]
+    fn golden_crlf_frames() {
+        let crlf = b"\r\n";
+        golden_client_with_eol(
+            ToServer::Send {
+                destination: "/q".into(),
+                transaction: Some("tx-1".into()),
+                headers: vec![],
+                body: Some(Bytes::from_static(b"hi")),
+            },
+            b"SEND\r\ndestination:/q\r\ntransaction:tx-1\r\n\r\nhi\x00",
+            crlf,
+        );
+        golden_client_with_eol(
+            ToServer::Subscribe {
+                destination: "/q".into(),
+                id: "s\r\n".into(),
+                ack: Some(AckMode::Client),
+            },
+            b"SUBSCRIBE\r\ndestination:/q\r\nid:s\\r\\n\r\nack:client\r\n\r\n\x00",
+            crlf,
+        );
+        golden_server_with_eol(
+            FromServer::Message {
+                destination: "/q".into(),
+                message_id: "1".into(),
+                subscription: "s".into(),
+                ack: None,
+                headers: vec![],
+                body: Some(Bytes::from_static(b"\r\n")),
+            },
+            b"MESSAGE\r\ndestination:/q\r\nmessage-id:1\r\nsubscription:s\r\n\
+              content-length:2\r\n\r\n\r\n\x00",
+            crlf,
+        );
+        golden_server_with_eol(
+            FromServer::Receipt {
+                receipt_id: "r-1".into(),
+            },
+            b"RECEIPT\r\nreceipt-id:r-1\r\n\r\n\x00",
+            crlf,
+        );
+
+        let msg = Message::from(ToServer::Begin {
+            transaction: "tx-1".into(),
+        });
+        let mut buffer = BytesMut::new();
+        ClientCodec::new()
+            .crlf_line_endings(true)
+            .encode(msg, &mut buffer)
+            .unwrap();
+        assert_eq!(&buffer[..], b"BEGIN\r\ntransaction:tx-1\r\n\r\n\x00");
     }
 }
@@ -0,0 +1,146 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::client::ClientCodec;
+use crate::{Message, Result, ToServer};
+
+/// How many negotiated intervals may pass without hearing from the server
+/// before it is considered dead, to allow for network latency
+const GRACE_FACTOR: u32 = 2;
+
+/// Negotiate heart-beat intervals from the client's `heart-beat` header and the
+/// server's reply, following the spec: each side uses the larger of what one end
+/// can do and what the other end wants, and 0 on either end disables it.
+/// Returns the (outgoing, incoming) intervals.
+pub(crate) fn negotiate(
+    client: (u32, u32),
+    server: (u32, u32),
+) -> (Option<Duration>, Option<Duration>) {
+    fn pick(can: u32, want: u32) -> Option<Duration> {
+        if can == 0 || want == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(can.max(want).into()))
+        }
+    }
+    (pick(client.0, server.1), pick(server.0, client.1))
+}
+
+/// Wraps the read half of a connection and fails with `TimedOut` if nothing at
+/// all (frames or heart-beats) is received within the allowed interval.
+pub(crate) struct HeartbeatMonitor<R> {
+    inner: R,
+    timeout: Option<Duration>,
+    deadline: Pin<Box<Sleep>>,
+    expired: bool,
+}
+
+impl<R> HeartbeatMonitor<R> {
+    pub(crate) fn new(inner: R, interval: Option<Duration>) -> HeartbeatMonitor<R> {
+        let timeout = interval.map(|i| i * GRACE_FACTOR);
+        let deadline = Box::pin(tokio::time::sleep(timeout.unwrap_or_default()));
+        HeartbeatMonitor {
+            inner,
+            timeout,
+            deadline,
+            expired: false,
+        }
+    }
+
+    /// The allowed interval, if it elapsed without hearing from the server
+    pub(crate) fn expired(&self) -> Option<Duration> {
+        self.timeout.filter(|_| self.expired)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HeartbeatMonitor<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                if let Some(timeout) = self.timeout {
+                    if buf.filled().len() > before {
+                        self.deadline.as_mut().reset(Instant::now() + timeout);
+                    }
+                }
+                Poll::Ready(res)
+            }
+            Poll::Pending => {
+                let timeout = match self.timeout {
+                    Some(timeout) => timeout,
+                    None => return Poll::Pending,
+                };
+                ready!(self.deadline.as_mut().poll(cx));
+                self.expired = true;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No heart-beat received from server in {:?}", timeout),
+                )))
+            }
+        }
+    }
+}
+
+/// Background task that owns the write half of a connection. Writes queued frames
+/// and sends an EOL heart-beat whenever nothing was sent for `interval`.
+/// Message bodies are written straight from their `Bytes`, not copied into a
+/// write buffer first.
+pub(crate) async fn write_loop<W>(
+    mut io: W,
+    mut codec: ClientCodec,
+    mut outgoing: mpsc::Receiver<Message<ToServer>>,
+    interval: Option<Duration>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let next = match interval {
+            Some(interval) => match tokio::time::timeout(interval, outgoing.next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    io.write_all(b"\n").await?;
+                    io.flush().await?;
+                    continue;
+                }
+            },
+            None => outgoing.next().await,
+        };
+        match next {
+            Some(msg) => {
+                io.write_all_buf(&mut codec.encode_buf(msg)).await?;
+                io.flush().await?;
+            }
+            None => break,
+        }
+    }
+    io.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_uses_max_and_zero_disables() {
+        let ms = |v| Some(Duration::from_millis(v));
+        assert_eq!(negotiate((0, 0), (1000, 1000)), (None, None));
+        assert_eq!(negotiate((1000, 1000), (0, 0)), (None, None));
+        assert_eq!(negotiate((1000, 5000), (2000, 500)), (ms(1000), ms(5000)));
+        assert_eq!(negotiate((100, 0), (0, 3000)), (ms(3000), None));
+        assert_eq!(negotiate((0, 100), (200, 0)), (None, ms(200)));
+    }
+}
@@ -1,28 +1,51 @@
 //! tokio-stomp - A library for asynchronous streaming of STOMP messages
 
-#[macro_use]
-extern crate nom;
-
+use bytes::{Buf, Bytes};
 use custom_debug::Debug;
 use frame::Frame;
 
 pub mod client;
+mod error;
+mod failover;
 mod frame;
+mod heartbeat;
+mod reconnect;
+pub mod server;
+mod session;
+mod tcp;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod url;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub use error::StompError;
+pub use failover::{EndpointHealth, Failover};
+pub use reconnect::{ConnectionEvent, ReconnectPolicy};
+pub use session::{Client, Receipt, Received, SubscribeOptions, Subscription, Transaction};
+pub use url::{Scheme, StompUrl};
 
-pub(crate) type Result<T> = std::result::Result<T, anyhow::Error>;
+pub(crate) type Result<T> = std::result::Result<T, StompError>;
 
 /// A representation of a STOMP frame
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Message<T> {
     /// The message content
     pub content: T,
-    /// Headers present in the frame which were not required by the content
+    /// Headers present in the frame which were not required by the content.
+    ///
+    /// When a frame is sent, these are written after the headers of the
+    /// content. As with repeated headers in the spec, the first one wins:
+    /// an extra header with the name of a header which the frame already
+    /// has, such as `destination` or `id`, is left out.
     pub extra_headers: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
-fn pretty_bytes(b: &Option<Vec<u8>>, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+fn pretty_bytes<B: AsRef<[u8]>>(b: &Option<B>, f: &mut std::fmt::Formatter) -> std::fmt::Result {
     if let Some(v) = b {
-        write!(f, "{}", String::from_utf8_lossy(v))
+        write!(f, "{}", String::from_utf8_lossy(v.as_ref()))
     } else {
         write!(f, "None")
     }
@@ -37,16 +60,20 @@ pub enum FromServer {
         version: String,
         session: Option<String>,
         server: Option<String>,
-        heartbeat: Option<String>,
+        heartbeat: Option<(u32, u32)>,
     },
     /// Conveys messages from subscriptions to the client
     Message {
         destination: String,
         message_id: String,
         subscription: String,
+        /// The id to acknowledge the message with, present when the
+        /// subscription uses `client` or `client-individual` acknowledgment
+        ack: Option<String>,
         headers: Vec<(String, String)>,
+        /// The body, which shares the memory it was received into
         #[debug(with = "pretty_bytes")]
-        body: Option<Vec<u8>>,
+        body: Option<Bytes>,
     },
     /// Sent from the server to the client once a server has successfully
     /// processed a client frame that requests a receipt
@@ -61,11 +88,12 @@ pub enum FromServer {
 
 // TODO tidy this lot up with traits?
 impl Message<FromServer> {
-    // fn to_frame<'a>(&'a self) -> Frame<'a> {
-    //     unimplemented!()
-    // }
+    fn to_frame<'a>(&'a self) -> Frame<'a> {
+        let mut frame = self.content.to_frame();
+        frame.add_headers(&self.extra_headers);
+        frame
+    }
 
-    // TODO make this undead
     fn from_frame<'a>(frame: Frame<'a>) -> Result<Message<FromServer>> {
         frame.to_server_msg()
     }
@@ -82,13 +110,19 @@ pub enum ToServer {
         login: Option<String>,
         passcode: Option<String>,
         heartbeat: Option<(u32, u32)>,
+        /// Send the STOMP command instead of CONNECT
+        stomp: bool,
     },
     /// Send a message to a destination in the messaging system
     Send {
         destination: String,
         transaction: Option<String>,
         headers: Vec<(String, String)>,
-        body: Option<Vec<u8>>,
+        /// The body, which is cheap to clone, so the same `Bytes` can be kept
+        /// for many sends. A connection writes it as it is, after the
+        /// headers, without copying it. Only `Encoder::encode` on
+        /// [`ClientCodec`](client::ClientCodec) copies it into the buffer.
+        body: Option<Bytes>,
     },
     /// Register to listen to a given destination
     Subscribe {
@@ -128,12 +162,30 @@ pub enum AckMode {
     ClientIndividual,
 }
 
+impl ToServer {
+    /// A SEND of `body` to `destination`, outside any transaction and
+    /// without headers.
+    ///
+    /// `body` may be any [`Buf`]. `Bytes` and `BytesMut` are taken over
+    /// without copying. Other buffers, such as several chained together, are
+    /// gathered into one `Bytes`.
+    pub fn send(destination: impl Into<String>, mut body: impl Buf) -> ToServer {
+        ToServer::Send {
+            destination: destination.into(),
+            transaction: None,
+            headers: vec![],
+            body: Some(body.copy_to_bytes(body.remaining())),
+        }
+    }
+}
+
 impl Message<ToServer> {
     fn to_frame<'a>(&'a self) -> Frame<'a> {
-        self.content.to_frame()
+        let mut frame = self.content.to_frame();
+        frame.add_headers(&self.extra_headers);
+        frame
     }
 
-    #[allow(dead_code)]
     fn from_frame<'a>(frame: Frame<'a>) -> Result<Message<ToServer>> {
         frame.to_client_msg()
     }
@@ -147,3 +199,12 @@ impl From<ToServer> for Message<ToServer> {
         }
     }
 }
+
+impl From<FromServer> for Message<FromServer> {
+    fn from(content: FromServer) -> Message<FromServer> {
+        Message {
+            content,
+            extra_headers: vec![],
+        }
+    }
+}
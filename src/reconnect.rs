@@ -0,0 +1,140 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::StompError;
+
+/// How a [`Client`](crate::Client) reconnects after losing its connection.
+///
+/// The delay before attempt `n` is `initial_delay * multiplier^(n - 1)`, capped
+/// at `max_delay`, and then reduced by a random fraction of up to `jitter` so
+/// that clients which lost the same broker do not all come back at once.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+impl ReconnectPolicy {
+    /// Start at 500ms, doubling up to 30s, with 50% jitter and no attempt limit
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+
+    /// The delay before the first attempt
+    pub fn initial_delay(mut self, delay: Duration) -> ReconnectPolicy {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// The longest delay between two attempts
+    pub fn max_delay(mut self, delay: Duration) -> ReconnectPolicy {
+        self.max_delay = delay;
+        self
+    }
+
+    /// The factor by which the delay grows after each failed attempt
+    pub fn multiplier(mut self, multiplier: f64) -> ReconnectPolicy {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// The largest fraction, between 0 and 1, by which a delay is randomly reduced
+    pub fn jitter(mut self, jitter: f64) -> ReconnectPolicy {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up after this many failed attempts in a row
+    pub fn max_attempts(mut self, attempts: u32) -> ReconnectPolicy {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    pub(crate) fn gives_up_after(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt >= max)
+    }
+
+    /// The delay before `attempt`, counting from 1
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        self.delay_with(attempt, random_fraction())
+    }
+
+    fn delay_with(&self, attempt: u32, random: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        let capped = backoff.min(self.max_delay.as_secs_f64());
+        Duration::from_secs_f64(capped * (1.0 - self.jitter * random))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy::new()
+    }
+}
+
+/// A change in the state of a reconnecting [`Client`](crate::Client)'s connection
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// The connection failed or was closed by the server
+    Lost(Arc<StompError>),
+    /// Waiting `delay` before reconnection attempt number `attempt`
+    Reconnecting { attempt: u32, delay: Duration },
+    /// An attempt failed
+    AttemptFailed(Arc<StompError>),
+    /// Connected again to the endpoint at `address`, and every active
+    /// subscription was re-issued
+    Reconnected { address: String },
+    /// The policy's attempt limit was reached; the client is closed
+    GaveUp,
+}
+
+/// A random number in `[0, 1)`
+pub(crate) fn random_fraction() -> f64 {
+    (random_bits() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// A random 128-bit id in hex, for ids which must not collide with those
+/// of other clients
+pub(crate) fn random_id() -> String {
+    format!("{:016x}{:016x}", random_bits(), random_bits())
+}
+
+/// 64 random bits, from the randomly seeded std hasher
+fn random_bits() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1))
+            .jitter(0.5);
+        let ms = |attempt, random| policy.delay_with(attempt, random).as_millis();
+        assert_eq!(ms(1, 0.0), 100);
+        assert_eq!(ms(2, 0.0), 200);
+        assert_eq!(ms(4, 0.0), 800);
+        assert_eq!(ms(5, 0.0), 1000);
+        assert_eq!(ms(100, 0.0), 1000);
+        assert_eq!(ms(2, 1.0), 100);
+        assert_eq!(ms(5, 0.5), 750);
+        assert!(policy.delay(3) <= Duration::from_millis(400));
+        assert!(policy.delay(3) >= Duration::from_millis(200));
+    }
+}
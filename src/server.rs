@@ -0,0 +1,280 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::frame;
+
+use crate::{FromServer, Message, Result, StompError, ToServer};
+
+/// The server side of the STOMP codec, for writing brokers and test servers.
+/// Decodes frames sent by clients and encodes frames sent by the server.
+///
+/// Like [`ClientCodec`](crate::client::ClientCodec), it refuses frames over
+/// its limits and keeps its place in a partly received frame between calls.
+///
+/// ```
+/// use tokio_stomp_2::server::ServerCodec;
+///
+/// let codec = ServerCodec::new()
+///     .max_frame_size(1024 * 1024)
+///     .max_headers(64);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerCodec {
+    parser: frame::Parser,
+}
+
+impl ServerCodec {
+    /// A codec with the default limits, the same as `ClientCodec`'s
+    pub fn new() -> ServerCodec {
+        ServerCodec {
+            parser: frame::Parser::new(frame::Limits::default()),
+        }
+    }
+
+    /// The largest frame accepted, in bytes from the command up to and
+    /// including the terminating NUL. Defaults to 100 MiB.
+    pub fn max_frame_size(mut self, bytes: usize) -> ServerCodec {
+        self.parser.limits.frame_size = bytes;
+        self
+    }
+
+    /// The most headers accepted in a frame. Defaults to 1000.
+    pub fn max_headers(mut self, count: usize) -> ServerCodec {
+        self.parser.limits.headers = count;
+        self
+    }
+
+    /// The longest header line accepted, in bytes. Defaults to 10 KiB.
+    pub fn max_header_length(mut self, bytes: usize) -> ServerCodec {
+        self.parser.limits.header_length = bytes;
+        self
+    }
+
+    /// The longest command accepted, in bytes. Defaults to 1024.
+    pub fn max_command_length(mut self, bytes: usize) -> ServerCodec {
+        self.parser.limits.command_length = bytes;
+        self
+    }
+}
+
+impl Default for ServerCodec {
+    fn default() -> ServerCodec {
+        ServerCodec::new()
+    }
+}
+
+impl Decoder for ServerCodec {
+    type Item = Message<ToServer>;
+    type Error = StompError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        frame::decode(src, &mut self.parser, Message::<ToServer>::from_frame)
+    }
+}
+
+impl Encoder<Message<FromServer>> for ServerCodec {
+    type Error = StompError;
+
+    fn encode(&mut self, item: Message<FromServer>, dst: &mut BytesMut) -> Result<()> {
+        item.to_frame().serialize(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientCodec;
+    use crate::AckMode;
+    use bytes::Bytes;
+
+    fn client_to_server(msg: ToServer) {
+        let mut buffer = BytesMut::new();
+        ClientCodec::new()
+            .encode(msg.clone().into(), &mut buffer)
+            .unwrap();
+        let wire = buffer.clone();
+        let decoded = ServerCodec::new().decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!(format!("{:?}", decoded.content), format!("{:?}", msg));
+        let mut again = BytesMut::new();
+        ClientCodec::new().encode(decoded, &mut again).unwrap();
+        assert_eq!(again, wire);
+    }
+
+    fn server_to_client(msg: FromServer) {
+        let mut buffer = BytesMut::new();
+        ServerCodec::new().encode(msg.into(), &mut buffer).unwrap();
+        let wire = buffer.clone();
+        let decoded = ClientCodec::new().decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+        let mut again = BytesMut::new();
+        ServerCodec::new().encode(decoded, &mut again).unwrap();
+        assert_eq!(again, wire);
+    }
+
+    #[test]
+    fn client_frames_roundtrip() {
+        client_to_server(ToServer::Connect {
+            accept_version: "1.2".into(),
+            host: "localhost".into(),
+            login: Some("guest".into()),
+            passcode: Some("guest".into()),
+            heartbeat: Some((1000, 2000)),
+            stomp: false,
+        });
+        client_to_server(ToServer::Connect {
+            accept_version: "1.1,1.2".into(),
+            host: "/".into(),
+            login: None,
+            passcode: None,
+            heartbeat: None,
+            stomp: true,
+        });
+        client_to_server(ToServer::Send {
+            destination: "/queue/a".into(),
+            transaction: None,
+            headers: vec![],
+            body: Some(Bytes::from_static(b"hello world")),
+        });
+        client_to_server(ToServer::Send {
+            destination: "/queue/a".into(),
+            transaction: Some("tx-1".into()),
+            headers: vec![],
+            body: None,
+        });
+        client_to_server(ToServer::Subscribe {
+            destination: "/queue/a".into(),
+            id: "sub-0".into(),
+            ack: Some(AckMode::ClientIndividual),
+        });
+        client_to_server(ToServer::Unsubscribe { id: "sub-0".into() });
+        client_to_server(ToServer::Ack {
+            id: "ack-1".into(),
+            transaction: Some("tx-1".into()),
+        });
+        client_to_server(ToServer::Nack {
+            id: "ack-2".into(),
+            transaction: None,
+        });
+        client_to_server(ToServer::Begin {
+            transaction: "tx-1".into(),
+        });
+        client_to_server(ToServer::Commit {
+            transaction: "tx-1".into(),
+        });
+        client_to_server(ToServer::Abort {
+            transaction: "tx-1".into(),
+        });
+        client_to_server(ToServer::Disconnect {
+            receipt: Some("bye".into()),
+        });
+    }
+
+    #[test]
+    fn server_frames_roundtrip() {
+        server_to_client(FromServer::Connected {
+            version: "1.2".into(),
+            session: Some("session-1".into()),
+            server: Some("test/0.1".into()),
+            heartbeat: Some((0, 10000)),
+        });
+        server_to_client(FromServer::Message {
+            destination: "/queue/a".into(),
+            message_id: "m-1".into(),
+            subscription: "sub-0".into(),
+            ack: Some("ack-1".into()),
+            headers: vec![("priority".into(), "4".into())],
+            body: Some(Bytes::from_static(b"contains \x00 a nul")),
+        });
+        server_to_client(FromServer::Receipt {
+            receipt_id: "r-1".into(),
+        });
+        server_to_client(FromServer::Error {
+            message: Some("bad things".into()),
+            body: Some(b"details".to_vec()),
+        });
+    }
+
+    #[test]
+    fn server_extra_headers_roundtrip() {
+        let frames = [
+            FromServer::Connected {
+                version: "1.2".into(),
+                session: None,
+                server: None,
+                heartbeat: None,
+            },
+            FromServer::Error {
+                message: Some("no such queue".into()),
+                body: None,
+            },
+            FromServer::Receipt {
+                receipt_id: "r-1".into(),
+            },
+        ];
+        for content in frames {
+            let msg = Message {
+                content,
+                extra_headers: vec![
+                    (b"receipt-id".to_vec(), b"r-2".to_vec()),
+                    (b"x-broker".to_vec(), b"b:1".to_vec()),
+                ],
+            };
+            let mut buffer = BytesMut::new();
+            ServerCodec::new().encode(msg.clone(), &mut buffer).unwrap();
+            let wire = buffer.clone();
+            let decoded = ClientCodec::new().decode(&mut buffer).unwrap().unwrap();
+            // A RECEIPT already has its own receipt-id, so only the first one is written
+            let expected = match msg.content {
+                FromServer::Receipt { .. } => &msg.extra_headers[1..],
+                _ => &msg.extra_headers[..],
+            };
+            assert_eq!(decoded.extra_headers, expected, "decoding {:?}", wire);
+            let mut again = BytesMut::new();
+            ServerCodec::new().encode(decoded, &mut again).unwrap();
+            assert_eq!(again, wire);
+        }
+    }
+
+    #[test]
+    fn message_body_has_content_length() {
+        let mut buffer = BytesMut::new();
+        let msg = FromServer::Message {
+            destination: "/queue/a".into(),
+            message_id: "m-1".into(),
+            subscription: "sub-0".into(),
+            ack: None,
+            headers: vec![],
+            body: Some(Bytes::from_static(b"\x00\x00")),
+        };
+        ServerCodec::new().encode(msg.into(), &mut buffer).unwrap();
+        assert_eq!(
+            &*buffer,
+            &b"MESSAGE\ndestination:/queue/a\nmessage-id:m-1\nsubscription:sub-0\ncontent-length:2\n\n\x00\x00\x00"[..]
+        );
+    }
+
+    #[test]
+    fn codec_applies_limits_across_reads() {
+        let mut codec = ServerCodec::new().max_frame_size(40).max_headers(2);
+        // A frame arriving in pieces is picked up where the last read ended
+        let mut buffer = BytesMut::from(&b"SEND\ndestination:/q\n"[..]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(b"\nabc\x00");
+        let msg = codec.decode(&mut buffer).unwrap().unwrap();
+        assert!(matches!(msg.content, ToServer::Send { .. }));
+
+        let mut buffer = BytesMut::from(&b"SEND\ndestination:/q\na:1\nb:2\n"[..]);
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(StompError::TooManyHeaders(2))
+        ));
+        let mut codec = ServerCodec::new().max_frame_size(40);
+        let mut buffer = BytesMut::from(&b"SEND\ndestination:/q\n\n0123456789abcdefghij"[..]);
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(StompError::FrameTooLarge(40))
+        ));
+    }
+}
@@ -0,0 +1,2000 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::future::BoxFuture;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::client::{ClientTransport, ConnectOptions, SessionInfo};
+use crate::failover::{EndpointHealth, Failover, FailoverConnector, Health};
+use crate::reconnect::{random_id, ConnectionEvent, ReconnectPolicy};
+use crate::{AckMode, FromServer, Message, Result, StompError, ToServer};
+
+type Delivery = Result<Message<FromServer>>;
+
+/// How long to wait for the RECEIPT of a DISCONNECT before closing anyway
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The receipt id of the DISCONNECT sent when switching to another endpoint
+const RETIRED_RECEIPT: &str = "disconnect-retired";
+
+/// The receipt id of the DISCONNECT which ends the session
+const CLOSED_RECEIPT: &str = "disconnect-closed";
+
+/// Destinations the broker turns into a temporary queue of the connection's
+/// own, delivering to it without a SUBSCRIBE
+const TEMP_QUEUE_PREFIX: &str = "/temp-queue/";
+
+/// How long [`Transaction::commit`] and [`Transaction::abort`] wait for the
+/// RECEIPT unless configured otherwise
+const DEFAULT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum Command {
+    Send(Message<ToServer>, oneshot::Sender<Result<()>>),
+    /// Send with a `receipt` header, then complete `confirmed` once the
+    /// RECEIPT arrives
+    SendReceipted {
+        msg: Message<ToServer>,
+        receipt: String,
+        sent: oneshot::Sender<Result<()>>,
+        confirmed: oneshot::Sender<Result<()>>,
+    },
+    Subscribe {
+        id: String,
+        options: SubscribeOptions,
+        deliveries: mpsc::UnboundedSender<Delivery>,
+        done: oneshot::Sender<Result<()>>,
+    },
+    Unsubscribe(String),
+    /// Send a request, making sure there is a subscription to `reply_to`
+    /// first unless it is a temporary queue, and pass on the reply carrying
+    /// `correlation_id`. A new subscription to `reply_to` uses the id
+    /// `subscription`.
+    Request {
+        msg: Message<ToServer>,
+        reply_to: String,
+        subscription: String,
+        correlation_id: String,
+        sent: oneshot::Sender<Result<()>>,
+        reply: oneshot::Sender<Delivery>,
+    },
+    Ack {
+        subscription: String,
+        id: String,
+        nack: bool,
+        transaction: Option<String>,
+        done: oneshot::Sender<Result<()>>,
+    },
+    Watch(mpsc::UnboundedSender<ConnectionEvent>),
+    Disconnect(oneshot::Sender<Result<()>>),
+}
+
+/// A handle to a STOMP connection which is driven by a background task.
+///
+/// Handles are cheap to clone and may be used from any task. Messages from the
+/// server are delivered to the [`Subscription`] they belong to.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use futures::prelude::*;
+/// use tokio_stomp_2::client::ConnectOptions;
+/// use tokio_stomp_2::{Client, ToServer};
+///
+/// let client = Client::connect(&ConnectOptions::new("127.0.0.1:61613")).await?;
+/// let mut queue = client.subscribe("queue.test").await?;
+/// client
+///     .send(ToServer::Send {
+///         destination: "queue.test".into(),
+///         transaction: None,
+///         headers: vec![],
+///         body: Some("Hello".into()),
+///     })
+///     .await?;
+/// while let Some(msg) = queue.next().await {
+///     println!("{:?}", msg?);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    commands: mpsc::UnboundedSender<Command>,
+    next_id: Arc<AtomicU64>,
+    health: Option<Health>,
+    reply_to: Arc<str>,
+    transaction_timeout: Duration,
+    session: Arc<Mutex<SessionInfo>>,
+}
+
+impl Client {
+    /// Connect to a STOMP server via TCP and start the background task
+    pub async fn connect(options: &ConnectOptions) -> Result<Client> {
+        Ok(Client::new(options.connect().await?))
+    }
+
+    /// Take over an established connection, driving it from a background task
+    pub fn new<S>(transport: ClientTransport<S>) -> Client
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Client::spawn(transport, None, None)
+    }
+
+    /// Connect to a STOMP server via TCP, and keep reconnecting following
+    /// `policy` whenever the connection is lost: on EOF, I/O errors, missed
+    /// heart-beats or an ERROR frame. Active subscriptions are re-issued with
+    /// their original ids, so their streams carry on. While disconnected,
+    /// `send` fails with [`StompError::ConnectionClosed`].
+    ///
+    /// The first connection is not retried; its error is returned.
+    pub async fn connect_reconnecting(
+        options: ConnectOptions,
+        policy: ReconnectPolicy,
+    ) -> Result<Client> {
+        Client::connect_failover(Failover::new(vec![options]), policy).await
+    }
+
+    /// As [`connect_reconnecting`](Client::connect_reconnecting), moving on to
+    /// the next of several brokers whenever one cannot be reached. Each
+    /// reconnection attempt tries every endpoint once.
+    ///
+    /// Fails with [`StompError::NoEndpoints`] if `failover` has no endpoints.
+    pub async fn connect_failover(failover: Failover, policy: ReconnectPolicy) -> Result<Client> {
+        if failover.endpoints().is_empty() {
+            return Err(StompError::NoEndpoints);
+        }
+        let mut connector = FailoverConnector::new(failover);
+        let (transport, _) = connector.connect().await?;
+        let health = connector.health();
+        let reconnect = Reconnect {
+            policy,
+            connector: Box::new(connector),
+        };
+        Ok(Client::spawn(transport, Some(reconnect), Some(health)))
+    }
+
+    fn spawn<S>(
+        transport: ClientTransport<S>,
+        reconnect: Option<Reconnect<S>>,
+        health: Option<Health>,
+    ) -> Client
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (commands, rx) = mpsc::unbounded();
+        let session = Arc::new(Mutex::new(transport.session().clone()));
+        tokio::spawn(run(transport, rx, reconnect, session.clone()));
+        Client {
+            commands,
+            next_id: Arc::new(AtomicU64::new(0)),
+            health,
+            reply_to: format!("{}reply-{}", TEMP_QUEUE_PREFIX, random_id()).into(),
+            transaction_timeout: DEFAULT_TRANSACTION_TIMEOUT,
+            session,
+        }
+    }
+
+    /// Have replies to [`Client::request`] sent to `destination` rather than
+    /// a temporary queue with a random name, unique to the client. Only
+    /// affects this handle and its clones.
+    ///
+    /// Destinations under `/temp-queue/` are left to the broker, which
+    /// delivers replies to them without a subscription. Any other destination
+    /// is subscribed to on first use, so it should not be shared with other
+    /// clients.
+    pub fn reply_to(mut self, destination: impl Into<String>) -> Client {
+        self.reply_to = destination.into().into();
+        self
+    }
+
+    /// Have [`Transaction::commit`] and [`Transaction::abort`] wait at most
+    /// `timeout` for the server's receipt rather than 30 seconds. Only affects
+    /// this handle and its clones.
+    pub fn transaction_timeout(mut self, timeout: Duration) -> Client {
+        self.transaction_timeout = timeout;
+        self
+    }
+
+    /// The state of each endpoint of a reconnecting client, in the order
+    /// they were given. Empty for clients created with [`Client::new`].
+    pub fn endpoints(&self) -> Vec<EndpointHealth> {
+        match &self.health {
+            Some(health) => health.lock().unwrap().clone(),
+            None => vec![],
+        }
+    }
+
+    /// What was agreed with the server when connecting. After a reconnection
+    /// this describes the new connection.
+    pub fn session(&self) -> SessionInfo {
+        self.session.lock().unwrap().clone()
+    }
+
+    /// The address of the endpoint the client is connected to, if known
+    pub fn active_endpoint(&self) -> Option<String> {
+        self.endpoints()
+            .into_iter()
+            .find(|e| e.active)
+            .map(|e| e.address)
+    }
+
+    /// A stream of changes to the connection, such as it being lost and
+    /// re-established. Only events after this call are reported.
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> {
+        let (watcher, events) = mpsc::unbounded();
+        let _ = self.commands.unbounded_send(Command::Watch(watcher));
+        events
+    }
+
+    /// Send a frame to the server. Completes once the frame has been passed on
+    /// to the connection.
+    pub async fn send(&self, msg: impl Into<Message<ToServer>>) -> Result<()> {
+        self.command(|done| Command::Send(msg.into(), done)).await
+    }
+
+    /// Subscribe to `destination`, using a generated subscription id.
+    /// The subscription is removed from the server when dropped.
+    pub async fn subscribe(&self, destination: impl Into<String>) -> Result<Subscription> {
+        self.subscribe_with(SubscribeOptions::new(destination))
+            .await
+    }
+
+    /// Subscribe to `destination` with the given acknowledgment mode.
+    /// With `Client` or `ClientIndividual`, acknowledge each message with
+    /// [`Received::ack`] or [`Received::nack`].
+    pub async fn subscribe_with_ack(
+        &self,
+        destination: impl Into<String>,
+        ack: AckMode,
+    ) -> Result<Subscription> {
+        self.subscribe_with(SubscribeOptions::new(destination).ack(ack))
+            .await
+    }
+
+    /// Subscribe with the given options, using a generated subscription id
+    pub async fn subscribe_with(&self, options: SubscribeOptions) -> Result<Subscription> {
+        let id = format!("sub-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (deliveries, messages) = mpsc::unbounded();
+        self.command(|done| Command::Subscribe {
+            id: id.clone(),
+            options,
+            deliveries,
+            done,
+        })
+        .await?;
+        Ok(Subscription {
+            id,
+            messages,
+            commands: self.commands.clone(),
+        })
+    }
+
+    /// Start a transaction. Frames sent through it are part of the transaction,
+    /// which is aborted if it is dropped before [`Transaction::commit`].
+    pub async fn begin(&self) -> Result<Transaction> {
+        let id = format!("tx-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let begin = ToServer::Begin {
+            transaction: id.clone(),
+        };
+        self.send(begin).await?;
+        Ok(Transaction {
+            id,
+            client: self.clone(),
+            open: true,
+        })
+    }
+
+    /// Send a frame with a `receipt` header, completing once the frame has
+    /// been passed on to the connection. The returned [`Receipt`] resolves
+    /// when the server confirms that it processed the frame.
+    pub async fn send_with_receipt(&self, msg: impl Into<Message<ToServer>>) -> Result<Receipt> {
+        let id = format!("rcpt-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (confirmed, confirmation) = oneshot::channel();
+        self.command(|sent| Command::SendReceipted {
+            msg: msg.into(),
+            receipt: id.clone(),
+            sent,
+            confirmed,
+        })
+        .await?;
+        Ok(Receipt { id, confirmation })
+    }
+
+    /// Send `body` to `destination` and wait up to `timeout` for the reply.
+    ///
+    /// The request carries `reply-to` and a random `correlation-id`; the
+    /// reply is the message sent to the reply destination with the same
+    /// `correlation-id`. The reply destination is shared by concurrent
+    /// requests; see [`reply_to`](Client::reply_to). Fails with
+    /// [`StompError::Timeout`] if no reply arrives in time.
+    ///
+    /// `body` is anything which converts to [`Bytes`] without copying, such as
+    /// `Vec<u8>`, `String` or `Bytes` itself. Any other [`Buf`](bytes::Buf)
+    /// can be passed through `Buf::copy_to_bytes`. The body is written to the
+    /// connection from where it is, without another copy.
+    pub async fn request(
+        &self,
+        destination: impl Into<String>,
+        body: impl Into<Bytes>,
+        timeout: Duration,
+    ) -> Result<Message<FromServer>> {
+        let correlation_id = random_id();
+        let subscription = format!("reply-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let reply_to = self.reply_to.to_string();
+        let msg = ToServer::Send {
+            destination: destination.into(),
+            transaction: None,
+            headers: vec![
+                ("reply-to".into(), reply_to.clone()),
+                ("correlation-id".into(), correlation_id.clone()),
+            ],
+            body: Some(body.into()),
+        };
+        let (reply, response) = oneshot::channel();
+        self.command(|sent| Command::Request {
+            msg: msg.into(),
+            reply_to,
+            subscription,
+            correlation_id,
+            sent,
+            reply,
+        })
+        .await?;
+        match tokio::time::timeout(timeout, response).await {
+            Ok(res) => res.unwrap_or(Err(StompError::ConnectionClosed)),
+            Err(_) => Err(StompError::Timeout("waiting for reply")),
+        }
+    }
+
+    /// Answer a message received as a request, sending `body` to its
+    /// `reply-to` destination with the same `correlation-id`
+    pub async fn reply(&self, request: &Message<FromServer>, body: impl Into<Bytes>) -> Result<()> {
+        let headers = match &request.content {
+            FromServer::Message { headers, .. } => &headers[..],
+            _ => &[],
+        };
+        let reply_to =
+            header(headers, "reply-to").ok_or(StompError::MissingHeader("reply-to".into()))?;
+        let correlation = header(headers, "correlation-id")
+            .map(|id| ("correlation-id".to_string(), id.to_string()));
+        let reply = ToServer::Send {
+            destination: reply_to.into(),
+            transaction: None,
+            headers: correlation.into_iter().collect(),
+            body: Some(body.into()),
+        };
+        self.send(reply).await
+    }
+
+    /// Disconnect gracefully, waiting for the server to confirm that it
+    /// processed every frame sent so far, then close the connection.
+    /// Receipts and messages which arrive until then are still passed on.
+    /// Every subscription ends and other handles stop working.
+    pub async fn disconnect(&self) -> Result<()> {
+        self.command(Command::Disconnect).await
+    }
+
+    async fn command(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<()>>) -> Command,
+    ) -> Result<()> {
+        request(&self.commands, command).await
+    }
+}
+
+/// The value of a header of a received message
+fn header<'a>(headers: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Pass a command to the background task and wait for its outcome
+async fn request(
+    commands: &mpsc::UnboundedSender<Command>,
+    command: impl FnOnce(oneshot::Sender<Result<()>>) -> Command,
+) -> Result<()> {
+    let (done, result) = oneshot::channel();
+    commands
+        .unbounded_send(command(done))
+        .map_err(|_| StompError::ConnectionClosed)?;
+    result.await.unwrap_or(Err(StompError::ConnectionClosed))
+}
+
+/// The settings of a subscription made with [`Client::subscribe_with`]
+#[derive(Debug, Clone)]
+pub struct SubscribeOptions {
+    destination: String,
+    ack: Option<AckMode>,
+    max_in_flight: Option<usize>,
+}
+
+impl SubscribeOptions {
+    /// Subscribe to `destination` with the server's default ack mode
+    pub fn new(destination: impl Into<String>) -> SubscribeOptions {
+        SubscribeOptions {
+            destination: destination.into(),
+            ack: None,
+            max_in_flight: None,
+        }
+    }
+
+    /// The acknowledgment mode
+    pub fn ack(mut self, ack: AckMode) -> SubscribeOptions {
+        self.ack = Some(ack);
+        self
+    }
+
+    /// Allow at most `max` delivered messages to be waiting for an ACK or
+    /// NACK, with `Client` or `ClientIndividual` acks.
+    ///
+    /// The limit is passed on to the broker as a prefetch hint, using both
+    /// ActiveMQ's `activemq.prefetchSize` and RabbitMQ's `prefetch-count`
+    /// headers. Should the broker send more anyway, up to `max` extra
+    /// messages are held back by the client until earlier ones are
+    /// acknowledged, without affecting other subscriptions. Beyond that the
+    /// client stops reading the connection until acknowledgements make room,
+    /// which holds up every subscription on it.
+    pub fn max_in_flight(mut self, max: usize) -> SubscribeOptions {
+        self.max_in_flight = Some(max.max(1));
+        self
+    }
+}
+
+/// The messages of one subscription, as a `Stream`.
+///
+/// Ends when the connection is closed, after yielding an error if the
+/// connection failed. Dropping it unsubscribes.
+pub struct Subscription {
+    id: String,
+    messages: mpsc::UnboundedReceiver<Delivery>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Subscription {
+    /// The id the subscription was registered with
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Result<Received>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let delivery = ready!(self.messages.poll_next_unpin(cx));
+        Poll::Ready(delivery.map(|res| {
+            res.map(|message| Received {
+                message,
+                subscription: self.id.clone(),
+                commands: self.commands.clone(),
+            })
+        }))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _ = self
+            .commands
+            .unbounded_send(Command::Unsubscribe(self.id.clone()));
+    }
+}
+
+/// A message delivered to a [`Subscription`]. Dereferences to the message.
+pub struct Received {
+    message: Message<FromServer>,
+    subscription: String,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Received {
+    /// The id to acknowledge the message with: its `ack` header. Absent for
+    /// subscriptions using `auto` acknowledgment.
+    pub fn ack_id(&self) -> Option<&str> {
+        match &self.message.content {
+            FromServer::Message { ack, .. } => ack.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Acknowledge the message. With `client` acknowledgment this also
+    /// acknowledges every earlier message of the subscription, and acking one of
+    /// those afterwards does nothing. Does nothing if there is no ack id.
+    pub async fn ack(&self) -> Result<()> {
+        self.settle(false, None).await
+    }
+
+    /// Tell the server the message was not consumed. Cumulative in the same
+    /// way as [`ack`](Received::ack).
+    pub async fn nack(&self) -> Result<()> {
+        self.settle(true, None).await
+    }
+
+    async fn settle(&self, nack: bool, transaction: Option<String>) -> Result<()> {
+        let id = match self.ack_id() {
+            Some(id) => id.to_string(),
+            None => return Ok(()),
+        };
+        request(&self.commands, |done| Command::Ack {
+            subscription: self.subscription.clone(),
+            id,
+            nack,
+            transaction,
+            done,
+        })
+        .await
+    }
+
+    pub fn into_message(self) -> Message<FromServer> {
+        self.message
+    }
+}
+
+impl fmt::Debug for Received {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Received")
+            .field("message", &self.message)
+            .field("subscription", &self.subscription)
+            .finish()
+    }
+}
+
+impl Deref for Received {
+    type Target = Message<FromServer>;
+
+    fn deref(&self) -> &Message<FromServer> {
+        &self.message
+    }
+}
+
+/// A transaction started with [`Client::begin`]. Sends, acks and nacks made
+/// through it carry its `transaction` header.
+///
+/// Dropping it without calling [`commit`](Transaction::commit) or
+/// [`abort`](Transaction::abort) sends ABORT, without waiting for a receipt.
+pub struct Transaction {
+    id: String,
+    client: Client,
+    open: bool,
+}
+
+impl Transaction {
+    /// The transaction id
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Send a frame as part of the transaction. The `transaction` of a
+    /// `Send`, `Ack` or `Nack` is set to this transaction.
+    pub async fn send(&self, msg: impl Into<Message<ToServer>>) -> Result<()> {
+        let mut msg = msg.into();
+        match &mut msg.content {
+            ToServer::Send { transaction, .. }
+            | ToServer::Ack { transaction, .. }
+            | ToServer::Nack { transaction, .. } => *transaction = Some(self.id.clone()),
+            _ => {}
+        }
+        self.client.send(msg).await
+    }
+
+    /// Acknowledge a message as part of the transaction, see [`Received::ack`]
+    pub async fn ack(&self, msg: &Received) -> Result<()> {
+        msg.settle(false, Some(self.id.clone())).await
+    }
+
+    /// Reject a message as part of the transaction, see [`Received::nack`]
+    pub async fn nack(&self, msg: &Received) -> Result<()> {
+        msg.settle(true, Some(self.id.clone())).await
+    }
+
+    /// Commit the transaction, waiting for the server's receipt. Fails with
+    /// [`StompError::Timeout`] if it does not arrive within the client's
+    /// [`transaction_timeout`](Client::transaction_timeout).
+    pub async fn commit(mut self) -> Result<()> {
+        self.finish(ToServer::Commit {
+            transaction: self.id.clone(),
+        })
+        .await
+    }
+
+    /// Abort the transaction, waiting for the server's receipt, for at most
+    /// as long as [`commit`](Transaction::commit)
+    pub async fn abort(mut self) -> Result<()> {
+        self.finish(ToServer::Abort {
+            transaction: self.id.clone(),
+        })
+        .await
+    }
+
+    async fn finish(&mut self, frame: ToServer) -> Result<()> {
+        self.open = false;
+        let receipt = self.client.send_with_receipt(frame).await?;
+        receipt.timeout(self.client.transaction_timeout).await
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.open {
+            let abort = ToServer::Abort {
+                transaction: self.id.clone(),
+            };
+            let (done, _) = oneshot::channel();
+            let _ = self
+                .client
+                .commands
+                .unbounded_send(Command::Send(abort.into(), done));
+        }
+    }
+}
+
+/// The server's confirmation of a frame sent with [`Client::send_with_receipt`].
+///
+/// Resolves once the RECEIPT arrives. Fails with [`StompError::ServerError`]
+/// if the server answers with an ERROR frame carrying the receipt id instead,
+/// and with [`StompError::ConnectionClosed`] if the connection is lost first.
+#[derive(Debug)]
+pub struct Receipt {
+    id: String,
+    confirmation: oneshot::Receiver<Result<()>>,
+}
+
+impl Receipt {
+    /// The receipt id sent in the frame's `receipt` header
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Wait for the receipt for at most `timeout`
+    pub async fn timeout(self, timeout: Duration) -> Result<()> {
+        match tokio::time::timeout(timeout, self).await {
+            Ok(res) => res,
+            Err(_) => Err(StompError::Timeout("waiting for RECEIPT")),
+        }
+    }
+}
+
+impl Future for Receipt {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.confirmation
+            .poll_unpin(cx)
+            .map(|res| res.unwrap_or(Err(StompError::ConnectionClosed)))
+    }
+}
+
+/// Establishes new connections for a reconnecting client
+pub(crate) trait Connector<S>: Send {
+    /// Connect to the next suitable endpoint, returning it with its address
+    fn connect(&mut self) -> BoxFuture<'static, Result<(ClientTransport<S>, String)>>;
+
+    /// While connected, resolves with a connection to a preferred endpoint
+    /// once it can be reached
+    fn upgrade(&mut self) -> BoxFuture<'static, (ClientTransport<S>, String)>;
+
+    /// The connection from [`upgrade`](Connector::upgrade) took over
+    fn switched(&mut self);
+}
+
+struct Reconnect<S> {
+    policy: ReconnectPolicy,
+    connector: Box<dyn Connector<S>>,
+}
+
+/// An active subscription, kept so that it can be re-issued after reconnecting
+struct Subscribed {
+    options: SubscribeOptions,
+    /// `None` for the subscription which receives replies to requests
+    deliveries: Option<mpsc::UnboundedSender<Delivery>>,
+    /// Ack ids of delivered messages which were not acknowledged yet, in order
+    unacked: VecDeque<String>,
+    /// Messages received beyond `max_in_flight`, delivered as earlier ones
+    /// are acknowledged. At most `max_in_flight` are held back.
+    held: VecDeque<Message<FromServer>>,
+}
+
+impl Subscribed {
+    fn new(
+        options: SubscribeOptions,
+        deliveries: Option<mpsc::UnboundedSender<Delivery>>,
+    ) -> Subscribed {
+        Subscribed {
+            options,
+            deliveries,
+            unacked: VecDeque::new(),
+            held: VecDeque::new(),
+        }
+    }
+
+    fn frame(&self, id: &str) -> Message<ToServer> {
+        let mut frame: Message<ToServer> = ToServer::Subscribe {
+            destination: self.options.destination.clone(),
+            id: id.into(),
+            ack: self.options.ack,
+        }
+        .into();
+        if let Some(max) = self.options.max_in_flight {
+            for key in ["activemq.prefetchSize", "prefetch-count"] {
+                frame
+                    .extra_headers
+                    .push((key.into(), max.to_string().into_bytes()));
+            }
+        }
+        frame
+    }
+
+    /// Whether as many messages as allowed are waiting to be acknowledged
+    fn saturated(&self) -> bool {
+        self.options
+            .max_in_flight
+            .is_some_and(|max| self.unacked.len() >= max)
+    }
+
+    /// Whether as many messages as allowed are held back, so that no more
+    /// should be read
+    fn full(&self) -> bool {
+        self.options
+            .max_in_flight
+            .is_some_and(|max| self.held.len() >= max)
+    }
+
+    /// Deliver a message, or hold it back if too many are unacknowledged
+    fn deliver(&mut self, msg: Message<FromServer>) {
+        self.held.push_back(msg);
+        self.release();
+    }
+
+    /// Deliver held back messages, in order, while the limit allows
+    fn release(&mut self) {
+        while !self.saturated() {
+            let msg = match self.held.pop_front() {
+                Some(msg) => msg,
+                None => return,
+            };
+            if let FromServer::Message { ack: Some(ack), .. } = &msg.content {
+                self.unacked.push_back(ack.clone());
+            }
+            if let Some(deliveries) = &self.deliveries {
+                let _ = deliveries.unbounded_send(Ok(msg));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    subscriptions: HashMap<String, Subscribed>,
+    watchers: Vec<mpsc::UnboundedSender<ConnectionEvent>>,
+    /// Requests waiting for a RECEIPT, by receipt id. Dropping them when the
+    /// connection is lost fails the requests.
+    receipts: HashMap<String, oneshot::Sender<Result<()>>>,
+    /// Requests waiting for a reply, by correlation id. Like receipts, they
+    /// fail when the connection is lost.
+    replies: HashMap<String, oneshot::Sender<Delivery>>,
+}
+
+impl State {
+    /// Whether a subscription cannot take any more messages for now
+    fn backlogged(&self) -> bool {
+        self.subscriptions.values().any(Subscribed::full)
+    }
+
+    fn emit(&mut self, event: ConnectionEvent) {
+        self.watchers
+            .retain(|watcher| watcher.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// The ACK or NACK frame for a message, unless an earlier cumulative
+    /// acknowledgment already covered it
+    fn settle(
+        &mut self,
+        subscription: &str,
+        id: String,
+        nack: bool,
+        transaction: Option<String>,
+    ) -> Option<Message<ToServer>> {
+        if let Some(sub) = self.subscriptions.get_mut(subscription) {
+            let pos = sub.unacked.iter().position(|unacked| *unacked == id)?;
+            match sub.options.ack {
+                Some(AckMode::Client) => drop(sub.unacked.drain(..=pos)),
+                _ => drop(sub.unacked.remove(pos)),
+            }
+            sub.release();
+        }
+        let frame = if nack {
+            ToServer::Nack { id, transaction }
+        } else {
+            ToServer::Ack { id, transaction }
+        };
+        Some(frame.into())
+    }
+
+    /// Pass a final error on to every subscription
+    fn fail(&self, e: &StompError) {
+        for deliveries in self.subscriptions.values().flat_map(|sub| &sub.deliveries) {
+            let _ = deliveries.unbounded_send(Err(e.duplicate()));
+        }
+    }
+}
+
+/// A connection which was replaced by one to a preferred endpoint. It is read
+/// until the RECEIPT of its DISCONNECT, so that messages and receipts which
+/// were already on their way are not lost.
+struct Retiring<S> {
+    transport: ClientTransport<S>,
+    /// Receipts which were pending when the connection was replaced
+    receipts: Vec<String>,
+    deadline: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl<S> Retiring<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Send DISCONNECT on `transport`, then keep reading it
+    async fn start(transport: ClientTransport<S>, state: &mut State) -> Option<Retiring<S>> {
+        let mut retiring = Retiring {
+            transport,
+            receipts: state.receipts.keys().cloned().collect(),
+            deadline: Box::pin(tokio::time::sleep(DISCONNECT_TIMEOUT)),
+        };
+        let disconnect = ToServer::Disconnect {
+            receipt: Some(RETIRED_RECEIPT.into()),
+        };
+        match retiring.transport.send(disconnect.into()).await {
+            Ok(()) => Some(retiring),
+            Err(_) => {
+                retiring.close(state).await;
+                None
+            }
+        }
+    }
+
+    /// The next frame, or `None` once the DISCONNECT was confirmed, the
+    /// connection failed or the timeout elapsed
+    async fn next(&mut self) -> Option<Message<FromServer>> {
+        match drain(&mut self.transport, RETIRED_RECEIPT, self.deadline.as_mut()).await {
+            Drained::Frame(msg) => Some(msg),
+            Drained::Done(_) => None,
+        }
+    }
+
+    /// Close the connection, failing the receipts which did not arrive
+    async fn close(mut self, state: &mut State) {
+        let _ = self.transport.close().await;
+        for id in self.receipts {
+            state.receipts.remove(&id);
+        }
+    }
+}
+
+/// A step in reading a connection after its DISCONNECT
+enum Drained {
+    Frame(Message<FromServer>),
+    /// The DISCONNECT was confirmed, or why it was not
+    Done(Result<()>),
+}
+
+/// Read the next frame from a connection which was sent DISCONNECT with
+/// `receipt`, until that RECEIPT arrives or `deadline` passes
+async fn drain<S>(
+    transport: &mut ClientTransport<S>,
+    receipt: &str,
+    deadline: Pin<&mut tokio::time::Sleep>,
+) -> Drained
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let msg = match future::select(transport.next(), deadline).await {
+        Either::Left((Some(Ok(msg)), _)) => msg,
+        Either::Left((Some(Err(e)), _)) => return Drained::Done(Err(e)),
+        Either::Left((None, _)) => return Drained::Done(Err(StompError::ConnectionClosed)),
+        Either::Right(_) => {
+            return Drained::Done(Err(StompError::Timeout("waiting for DISCONNECT receipt")))
+        }
+    };
+    match &msg.content {
+        FromServer::Receipt { receipt_id } if receipt_id == receipt => Drained::Done(Ok(())),
+        _ => Drained::Frame(msg),
+    }
+}
+
+/// Pass on a frame which arrived after DISCONNECT. Messages waiting for an
+/// ack are left out: they can no longer be acknowledged on this connection,
+/// and the broker delivers them again.
+fn dispatch_drained(state: &mut State, msg: Message<FromServer>) -> Option<StompError> {
+    match &msg.content {
+        FromServer::Message { ack: Some(_), .. } => None,
+        _ => dispatch(state, msg),
+    }
+}
+
+/// Disconnect gracefully. Frames which arrive before the RECEIPT of the
+/// DISCONNECT, such as receipts for earlier sends, are still passed on.
+async fn disconnect<S>(transport: &mut ClientTransport<S>, state: &mut State) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let disconnect = ToServer::Disconnect {
+        receipt: Some(CLOSED_RECEIPT.into()),
+    };
+    let deadline = tokio::time::sleep(DISCONNECT_TIMEOUT);
+    futures::pin_mut!(deadline);
+    let mut confirmed = transport.send(disconnect.into()).await;
+    while confirmed.is_ok() {
+        match drain(transport, CLOSED_RECEIPT, deadline.as_mut()).await {
+            Drained::Frame(msg) => {
+                if let Some(e) = dispatch_drained(state, msg) {
+                    confirmed = Err(e);
+                }
+            }
+            Drained::Done(res) => {
+                confirmed = res;
+                break;
+            }
+        }
+    }
+    let closed = transport.close().await;
+    confirmed.and(closed)
+}
+
+/// What `serve` waits for
+enum Event<S> {
+    Frame(Option<Result<Message<FromServer>>>),
+    /// A frame from the retiring connection, `None` once it is done
+    Retired(Option<Message<FromServer>>),
+    Command(Option<Command>),
+    Switch(Box<ClientTransport<S>>, String),
+}
+
+/// Why `serve` stopped
+enum Exit<S> {
+    /// Closed on request, or every handle was dropped
+    Closed,
+    /// The connection failed or was closed by the server
+    Lost(StompError),
+    /// A connection to a preferred endpoint is ready to take over
+    Switch(Box<ClientTransport<S>>, String),
+}
+
+/// The background task: forwards commands to the server and delivers
+/// incoming messages to their subscriptions by the `subscription` header.
+/// With a reconnect policy, a lost connection is replaced and the active
+/// subscriptions are re-issued with their original ids.
+async fn run<S>(
+    mut transport: ClientTransport<S>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut reconnect: Option<Reconnect<S>>,
+    session: Arc<Mutex<SessionInfo>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut state = State::default();
+    let mut retiring = None;
+    loop {
+        let upgrade = match &mut reconnect {
+            Some(reconnect) => reconnect.connector.upgrade(),
+            None => future::pending().boxed(),
+        };
+        let exit = serve(
+            &mut transport,
+            &mut retiring,
+            &mut commands,
+            &mut state,
+            upgrade,
+        )
+        .await;
+        let lost = match exit {
+            Exit::Closed => return,
+            Exit::Switch(mut next, address) => {
+                // If the subscriptions cannot be moved, stay where they are
+                if replay(&mut next, &mut state).await.is_err() {
+                    continue;
+                }
+                let old = std::mem::replace(&mut transport, *next);
+                if let Some(previous) = retiring.take() {
+                    previous.close(&mut state).await;
+                }
+                retiring = Retiring::start(old, &mut state).await;
+                if let Some(reconnect) = &mut reconnect {
+                    reconnect.connector.switched();
+                }
+                *session.lock().unwrap() = transport.session().clone();
+                state.emit(ConnectionEvent::Reconnected { address });
+                continue;
+            }
+            Exit::Lost(e) => e,
+        };
+        retiring = None;
+        state.receipts.clear();
+        state.replies.clear();
+        let reconnect = match &mut reconnect {
+            Some(reconnect) => reconnect,
+            None => return state.fail(&lost),
+        };
+        state.emit(ConnectionEvent::Lost(Arc::new(lost)));
+        let (next, address) = match reestablish(reconnect, &mut commands, &mut state).await {
+            Some(connected) => connected,
+            None => return,
+        };
+        transport = next;
+        *session.lock().unwrap() = transport.session().clone();
+        state.emit(ConnectionEvent::Reconnected { address });
+    }
+}
+
+async fn serve<S>(
+    transport: &mut ClientTransport<S>,
+    retiring: &mut Option<Retiring<S>>,
+    commands: &mut mpsc::UnboundedReceiver<Command>,
+    state: &mut State,
+    mut upgrade: BoxFuture<'static, (ClientTransport<S>, String)>,
+) -> Exit<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    loop {
+        // With too many messages held back, stop reading the connection and
+        // let the server wait until acknowledgements make room
+        let backlogged = state.backlogged();
+        let event = {
+            let incoming = if backlogged {
+                future::pending().right_future()
+            } else {
+                transport.next().map(Event::Frame).left_future()
+            };
+            let retired = match retiring {
+                Some(retiring) => retiring.next().map(Event::Retired).left_future(),
+                None => future::pending().right_future(),
+            };
+            futures::pin_mut!(retired);
+            let incoming = future::select(incoming, retired).map(|e| e.factor_first().0);
+            let control = future::select(
+                commands.next().map(Event::Command),
+                upgrade
+                    .as_mut()
+                    .map(|(next, address)| Event::Switch(Box::new(next), address)),
+            )
+            .map(|e| e.factor_first().0);
+            future::select(incoming, control).await.factor_first().0
+        };
+        let command = match event {
+            Event::Frame(Some(Ok(msg))) => match dispatch(state, msg) {
+                Some(e) => return Exit::Lost(e),
+                None => continue,
+            },
+            Event::Frame(Some(Err(e))) => return Exit::Lost(e),
+            Event::Frame(None) => return Exit::Lost(StompError::ConnectionClosed),
+            Event::Retired(Some(msg)) => {
+                // Messages waiting for an ack are redelivered on the new
+                // connection, where they can be acknowledged
+                if dispatch_drained(state, msg).is_none() {
+                    continue;
+                }
+                if let Some(retiring) = retiring.take() {
+                    retiring.close(state).await;
+                }
+                continue;
+            }
+            Event::Retired(None) => {
+                if let Some(retiring) = retiring.take() {
+                    retiring.close(state).await;
+                }
+                continue;
+            }
+            Event::Command(Some(command)) => command,
+            // Every handle and subscription is gone
+            Event::Command(None) => {
+                if let Some(retiring) = retiring.take() {
+                    retiring.close(state).await;
+                }
+                let _ = disconnect(transport, state).await;
+                return Exit::Closed;
+            }
+            Event::Switch(next, address) => return Exit::Switch(next, address),
+        };
+        match command {
+            Command::Send(msg, done) => {
+                let _ = done.send(transport.send(msg).await);
+            }
+            Command::SendReceipted {
+                mut msg,
+                receipt,
+                sent,
+                confirmed,
+            } => {
+                // A receipt header of the caller's own would win over ours
+                msg.extra_headers.retain(|(k, _)| k != b"receipt");
+                match &mut msg.content {
+                    ToServer::Disconnect { receipt: r } => *r = Some(receipt.clone()),
+                    _ => msg
+                        .extra_headers
+                        .push((b"receipt".to_vec(), receipt.clone().into_bytes())),
+                }
+                let res = transport.send(msg).await;
+                if res.is_ok() {
+                    // Receipts which timed out or were dropped wait no longer
+                    state.receipts.retain(|_, done| !done.is_canceled());
+                    state.receipts.insert(receipt, confirmed);
+                }
+                let _ = sent.send(res);
+            }
+            Command::Subscribe {
+                id,
+                options,
+                deliveries,
+                done,
+            } => {
+                let sub = Subscribed::new(options, Some(deliveries));
+                let frame = sub.frame(&id);
+                state.subscriptions.insert(id, sub);
+                let _ = done.send(transport.send(frame).await);
+            }
+            Command::Request {
+                msg,
+                reply_to,
+                subscription,
+                correlation_id,
+                sent,
+                reply,
+            } => {
+                let mut res = Ok(());
+                let subscribed = reply_to.starts_with(TEMP_QUEUE_PREFIX)
+                    || state
+                        .subscriptions
+                        .values()
+                        .any(|sub| sub.deliveries.is_none() && sub.options.destination == reply_to);
+                if !subscribed {
+                    let sub = Subscribed::new(SubscribeOptions::new(reply_to), None);
+                    res = transport.send(sub.frame(&subscription)).await;
+                    state.subscriptions.insert(subscription, sub);
+                }
+                if res.is_ok() {
+                    res = transport.send(msg).await;
+                }
+                if res.is_ok() {
+                    state.replies.retain(|_, reply| !reply.is_canceled());
+                    state.replies.insert(correlation_id, reply);
+                }
+                let _ = sent.send(res);
+            }
+            Command::Unsubscribe(id) => {
+                if state.subscriptions.remove(&id).is_some() {
+                    let _ = transport.send(ToServer::Unsubscribe { id }.into()).await;
+                }
+            }
+            Command::Ack {
+                subscription,
+                id,
+                nack,
+                transaction,
+                done,
+            } => {
+                let res = match state.settle(&subscription, id, nack, transaction) {
+                    Some(frame) => transport.send(frame).await,
+                    None => Ok(()),
+                };
+                let _ = done.send(res);
+            }
+            Command::Watch(watcher) => state.watchers.push(watcher),
+            Command::Disconnect(done) => {
+                if let Some(retiring) = retiring.take() {
+                    retiring.close(state).await;
+                }
+                let _ = done.send(disconnect(transport, state).await);
+                return Exit::Closed;
+            }
+        }
+    }
+}
+
+/// Pass a frame from the server on to whoever is waiting for it. Returns the
+/// error to fail the connection with for an ERROR frame.
+fn dispatch(state: &mut State, msg: Message<FromServer>) -> Option<StompError> {
+    match &msg.content {
+        FromServer::Message {
+            subscription,
+            headers,
+            ..
+        } => match state.subscriptions.get_mut(subscription) {
+            Some(sub) if sub.deliveries.is_some() => sub.deliver(msg),
+            // A reply, on the reply subscription or on one which
+            // the broker set up by itself
+            _ => {
+                let waiting =
+                    header(headers, "correlation-id").and_then(|id| state.replies.remove(id));
+                if let Some(reply) = waiting {
+                    let _ = reply.send(Ok(msg));
+                }
+            }
+        },
+        FromServer::Receipt { receipt_id } => {
+            if let Some(done) = state.receipts.remove(receipt_id) {
+                let _ = done.send(Ok(()));
+            }
+        }
+        FromServer::Error { .. } => {
+            let receipt = msg.extra_headers.iter().find(|(k, _)| k == b"receipt-id");
+            let waiting =
+                receipt.and_then(|(_, id)| state.receipts.remove(&*String::from_utf8_lossy(id)));
+            if let Some(confirmed) = waiting {
+                let e = StompError::ServerError(Box::new(msg.clone()));
+                let _ = confirmed.send(Err(e));
+            }
+            return Some(StompError::ServerError(Box::new(msg)));
+        }
+        _ => {}
+    }
+    None
+}
+
+/// Connect again following the policy, then re-issue every subscription.
+/// Commands are handled while waiting: sends fail, subscription changes are
+/// recorded for when the connection is back. Returns the new connection and
+/// its address, or `None` if the client was closed or the policy gave up.
+async fn reestablish<S>(
+    reconnect: &mut Reconnect<S>,
+    commands: &mut mpsc::UnboundedReceiver<Command>,
+    state: &mut State,
+) -> Option<(ClientTransport<S>, String)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut attempt = 0;
+    loop {
+        if reconnect.policy.gives_up_after(attempt) {
+            state.emit(ConnectionEvent::GaveUp);
+            state.fail(&StompError::ConnectionClosed);
+            return None;
+        }
+        attempt += 1;
+        let delay = reconnect.policy.delay(attempt);
+        state.emit(ConnectionEvent::Reconnecting { attempt, delay });
+        let mut sleep = Box::pin(tokio::time::sleep(delay));
+        loop {
+            match future::select(sleep, commands.next()).await {
+                Either::Left(_) => break,
+                Either::Right((Some(command), pending)) => {
+                    if !offline(command, state) {
+                        return None;
+                    }
+                    sleep = pending;
+                }
+                Either::Right((None, _)) => return None,
+            }
+        }
+        let res = match reconnect.connector.connect().await {
+            Ok((mut transport, address)) => replay(&mut transport, state)
+                .await
+                .map(|_| (transport, address)),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(connected) => return Some(connected),
+            Err(e) => state.emit(ConnectionEvent::AttemptFailed(Arc::new(e))),
+        }
+    }
+}
+
+/// Re-issue every active subscription on a new connection. Messages which
+/// were not acknowledged will be redelivered, so once every subscription is
+/// re-issued they are forgotten.
+async fn replay<S>(transport: &mut ClientTransport<S>, state: &mut State) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    for (id, sub) in &state.subscriptions {
+        transport.send(sub.frame(id)).await?;
+    }
+    for sub in state.subscriptions.values_mut() {
+        sub.unacked.clear();
+        sub.held.clear();
+    }
+    Ok(())
+}
+
+/// Handle a command while there is no connection.
+/// Returns false if the client should close.
+fn offline(command: Command, state: &mut State) -> bool {
+    match command {
+        Command::Send(_, done)
+        | Command::SendReceipted { sent: done, .. }
+        | Command::Request { sent: done, .. } => {
+            let _ = done.send(Err(StompError::ConnectionClosed));
+        }
+        Command::Subscribe {
+            id,
+            options,
+            deliveries,
+            done,
+        } => {
+            let sub = Subscribed::new(options, Some(deliveries));
+            state.subscriptions.insert(id, sub);
+            let _ = done.send(Ok(()));
+        }
+        Command::Unsubscribe(id) => {
+            state.subscriptions.remove(&id);
+        }
+        Command::Ack { done, .. } => {
+            let _ = done.send(Err(StompError::ConnectionClosed));
+        }
+        Command::Watch(watcher) => state.watchers.push(watcher),
+        Command::Disconnect(done) => {
+            let _ = done.send(Ok(()));
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{connect_with, ClientCodec, ClientTransport};
+    use crate::server::ServerCodec;
+    use crate::testing::handshake;
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::{Encoder, Framed};
+
+    type Server<S = DuplexStream> = Framed<S, ServerCodec>;
+
+    async fn accept(listener: &TcpListener) -> Server<TcpStream> {
+        handshake(listener.accept().await.unwrap().0, None).await.0
+    }
+
+    async fn pair() -> (Client, Server) {
+        let (client, server) = tokio::io::duplex(4096);
+        let handshake = tokio::spawn(async { handshake(server, None).await.0 });
+        let (transport, session) = connect_with(client, &ConnectOptions::new("memory:0"))
+            .await
+            .unwrap();
+        let transport = ClientTransport::new(transport, session);
+        (Client::new(transport), handshake.await.unwrap())
+    }
+
+    fn message(subscription: &str, body: &str) -> Message<FromServer> {
+        FromServer::Message {
+            destination: "/q".into(),
+            message_id: body.into(),
+            subscription: subscription.into(),
+            ack: None,
+            headers: vec![],
+            body: Some(Bytes::copy_from_slice(body.as_bytes())),
+        }
+        .into()
+    }
+
+    fn acked(subscription: &str, ack: &str) -> Message<FromServer> {
+        let mut msg = message(subscription, ack);
+        if let FromServer::Message { ack: a, .. } = &mut msg.content {
+            *a = Some(ack.into());
+        }
+        msg
+    }
+
+    async fn expect_subscribe<S>(server: &mut Server<S>) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match server.next().await.unwrap().unwrap().content {
+            ToServer::Subscribe { id, .. } => id,
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    fn body(msg: Option<Result<Received>>) -> Bytes {
+        match msg.unwrap().unwrap().into_message().content {
+            FromServer::Message { body, .. } => body.unwrap(),
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_routed_by_subscription() {
+        let (client, mut server) = pair().await;
+        let mut first = client.subscribe("/a").await.unwrap();
+        let first_id = expect_subscribe(&mut server).await;
+        let mut second = client.clone().subscribe("/b").await.unwrap();
+        let second_id = expect_subscribe(&mut server).await;
+        assert_eq!(first.id(), first_id);
+        assert_eq!(second.id(), second_id);
+        assert_ne!(first_id, second_id);
+
+        server.send(message(&second_id, "two")).await.unwrap();
+        server.send(message(&first_id, "one")).await.unwrap();
+        server.send(message("unknown", "lost")).await.unwrap();
+        assert_eq!(body(first.next().await), "one");
+        assert_eq!(body(second.next().await), "two");
+
+        drop(first);
+        match server.next().await.unwrap().unwrap().content {
+            ToServer::Unsubscribe { id } => assert_eq!(id, first_id),
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_ends_subscriptions() {
+        let (client, mut server) = pair().await;
+        let mut sub = client.subscribe("/a").await.unwrap();
+        expect_subscribe(&mut server).await;
+        client
+            .send(ToServer::Send {
+                destination: "/a".into(),
+                transaction: None,
+                headers: vec![],
+                body: None,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.next().await.unwrap().unwrap().content,
+            ToServer::Send { .. }
+        ));
+
+        let disconnect = tokio::spawn({
+            let client = client.clone();
+            async move { client.disconnect().await }
+        });
+        match server.next().await.unwrap().unwrap().content {
+            ToServer::Disconnect {
+                receipt: Some(receipt_id),
+            } => {
+                let receipt = FromServer::Receipt { receipt_id };
+                server.send(receipt.into()).await.unwrap();
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+        disconnect.await.unwrap().unwrap();
+        assert!(server.next().await.is_none());
+        assert!(sub.next().await.is_none());
+        assert!(matches!(
+            client.subscribe("/b").await,
+            Err(StompError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_error_is_delivered_to_subscriptions() {
+        let (client, mut server) = pair().await;
+        let mut sub = client.subscribe("/a").await.unwrap();
+        expect_subscribe(&mut server).await;
+        let error = FromServer::Error {
+            message: Some("boom".into()),
+            body: None,
+        };
+        server.send(error.into()).await.unwrap();
+        assert!(matches!(
+            sub.next().await,
+            Some(Err(StompError::ServerError(_)))
+        ));
+        assert!(sub.next().await.is_none());
+    }
+
+    /// Receive three messages with ack ids a1 to a3 on a subscription with the
+    /// given ack mode, returning the subscription and its messages
+    async fn receive_acked(mode: AckMode) -> (Client, Subscription, Server, Vec<Received>) {
+        let (client, mut server) = pair().await;
+        let mut sub = client.subscribe_with_ack("/a", mode).await.unwrap();
+        let id = match server.next().await.unwrap().unwrap().content {
+            ToServer::Subscribe { id, ack, .. } => {
+                assert_eq!(format!("{:?}", ack), format!("{:?}", Some(mode)));
+                id
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        };
+        let mut received = vec![];
+        for ack in &["a1", "a2", "a3"] {
+            server.send(acked(&id, ack)).await.unwrap();
+            received.push(sub.next().await.unwrap().unwrap());
+        }
+        (client, sub, server, received)
+    }
+
+    async fn expect_ack(server: &mut Server) -> (&'static str, String) {
+        match server.next().await.unwrap().unwrap().content {
+            ToServer::Ack { id, .. } => ("ACK", id),
+            ToServer::Nack { id, .. } => ("NACK", id),
+            other => panic!("Unexpected frame: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_acks_are_cumulative() {
+        let (_client, _sub, mut server, received) = receive_acked(AckMode::Client).await;
+        assert_eq!(received[1].ack_id(), Some("a2"));
+        received[1].ack().await.unwrap();
+        assert_eq!(expect_ack(&mut server).await, ("ACK", "a2".into()));
+        // Already covered by the ack of a2, so nothing is sent
+        received[0].ack().await.unwrap();
+        received[2].nack().await.unwrap();
+        assert_eq!(expect_ack(&mut server).await, ("NACK", "a3".into()));
+    }
+
+    #[tokio::test]
+    async fn client_individual_acks_each_message() {
+        let (_client, _sub, mut server, received) = receive_acked(AckMode::ClientIndividual).await;
+        received[1].ack().await.unwrap();
+        assert_eq!(expect_ack(&mut server).await, ("ACK", "a2".into()));
+        received[0].nack().await.unwrap();
+        assert_eq!(expect_ack(&mut server).await, ("NACK", "a1".into()));
+        received[0].ack().await.unwrap();
+        received[2].ack().await.unwrap();
+        assert_eq!(expect_ack(&mut server).await, ("ACK", "a3".into()));
+    }
+
+    #[tokio::test]
+    async fn deliveries_pause_at_max_in_flight() {
+        let (client, mut server) = pair().await;
+        let options = SubscribeOptions::new("/a")
+            .ack(AckMode::ClientIndividual)
+            .max_in_flight(2);
+        let mut sub = client.subscribe_with(options).await.unwrap();
+        assert_eq!(
+            wire(next_frame(&mut server).await),
+            "SUBSCRIBE\ndestination:/a\nid:sub-0\nack:client-individual\n\
+             activemq.prefetchSize:2\nprefetch-count:2\n\n\x00"
+        );
+        let mut other = client.subscribe("/b").await.unwrap();
+        let other_id = expect_subscribe(&mut server).await;
+
+        for ack in &["a1", "a2", "a3"] {
+            server.send(acked("sub-0", ack)).await.unwrap();
+        }
+        let first = sub.next().await.unwrap().unwrap();
+        sub.next().await.unwrap().unwrap();
+        let third = tokio::time::timeout(Duration::from_millis(50), sub.next()).await;
+        assert!(third.is_err(), "delivered past the in-flight limit");
+
+        // The connection is still read for other subscriptions and receipts
+        server.send(message(&other_id, "other")).await.unwrap();
+        assert_eq!(body(other.next().await), "other");
+        let pending = client.send_with_receipt(send_frame("one")).await.unwrap();
+        let frame = next_frame(&mut server).await;
+        server.send(receipt(&frame)).await.unwrap();
+        pending.await.unwrap();
+
+        first.ack().await.unwrap();
+        assert_eq!(expect_ack(&mut server).await, ("ACK", "a1".into()));
+        let third = sub.next().await.unwrap().unwrap();
+        assert_eq!(third.ack_id(), Some("a3"));
+    }
+
+    #[tokio::test]
+    async fn reading_stops_while_held_back_messages_are_full() {
+        let (client, mut server) = pair().await;
+        let options = SubscribeOptions::new("/a")
+            .ack(AckMode::ClientIndividual)
+            .max_in_flight(2);
+        let mut sub = client.subscribe_with(options).await.unwrap();
+        expect_subscribe(&mut server).await;
+        let mut other = client.subscribe("/b").await.unwrap();
+        let other_id = expect_subscribe(&mut server).await;
+
+        // Two are delivered and two held back, which is all that is allowed
+        for ack in &["a1", "a2", "a3", "a4"] {
+            server.send(acked("sub-0", ack)).await.unwrap();
+        }
+        server.send(message(&other_id, "other")).await.unwrap();
+        let first = sub.next().await.unwrap().unwrap();
+        sub.next().await.unwrap().unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), other.next()).await;
+        assert!(waiting.is_err(), "read past the held back messages");
+
+        // Commands are still served, so the ack makes room
+        first.ack().await.unwrap();
+        assert_eq!(expect_ack(&mut server).await, ("ACK", "a1".into()));
+        assert_eq!(body(other.next().await), "other");
+        let third = sub.next().await.unwrap().unwrap();
+        assert_eq!(third.ack_id(), Some("a3"));
+    }
+
+    /// A client frame as it is written to the wire
+    fn wire(msg: Message<ToServer>) -> String {
+        let mut buffer = bytes::BytesMut::new();
+        ClientCodec::new().encode(msg, &mut buffer).unwrap();
+        String::from_utf8(buffer.to_vec()).unwrap()
+    }
+
+    async fn next_frame(server: &mut Server) -> Message<ToServer> {
+        server.next().await.unwrap().unwrap()
+    }
+
+    fn receipt(msg: &Message<ToServer>) -> Message<FromServer> {
+        let (_, id) = msg
+            .extra_headers
+            .iter()
+            .find(|(k, _)| k == b"receipt")
+            .unwrap();
+        FromServer::Receipt {
+            receipt_id: String::from_utf8(id.clone()).unwrap(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn transaction_tags_frames_and_waits_for_receipt() {
+        let (client, _sub, mut server, received) = receive_acked(AckMode::ClientIndividual).await;
+        let tx = client.begin().await.unwrap();
+        assert_eq!(
+            wire(next_frame(&mut server).await),
+            "BEGIN\ntransaction:tx-1\n\n\x00"
+        );
+        tx.send(ToServer::Send {
+            destination: "/q".into(),
+            transaction: None,
+            headers: vec![],
+            body: Some(Bytes::from_static(b"hi")),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            wire(next_frame(&mut server).await),
+            "SEND\ndestination:/q\ntransaction:tx-1\n\nhi\x00"
+        );
+        tx.ack(&received[0]).await.unwrap();
+        assert_eq!(
+            wire(next_frame(&mut server).await),
+            "ACK\nid:a1\ntransaction:tx-1\n\n\x00"
+        );
+
+        let commit = tokio::spawn(tx.commit());
+        let frame = next_frame(&mut server).await;
+        assert_eq!(
+            wire(frame.clone()),
+            "COMMIT\ntransaction:tx-1\nreceipt:rcpt-2\n\n\x00"
+        );
+        server.send(receipt(&frame)).await.unwrap();
+        commit.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_commit_times_out() {
+        let (client, mut server) = pair().await;
+        let client = client.transaction_timeout(Duration::from_millis(20));
+        let tx = client.begin().await.unwrap();
+        next_frame(&mut server).await;
+        let commit = tokio::spawn(tx.commit());
+        assert!(matches!(
+            next_frame(&mut server).await.content,
+            ToServer::Commit { .. }
+        ));
+        // The server never sends the RECEIPT
+        assert!(matches!(commit.await.unwrap(), Err(StompError::Timeout(_))));
+    }
+
+    fn send_frame(body: &str) -> ToServer {
+        ToServer::Send {
+            destination: "/q".into(),
+            transaction: None,
+            headers: vec![],
+            body: Some(Bytes::copy_from_slice(body.as_bytes())),
+        }
+    }
+
+    #[tokio::test]
+    async fn receipts_are_confirmed_or_time_out() {
+        let (client, mut server) = pair().await;
+        let first = client.send_with_receipt(send_frame("one")).await.unwrap();
+        let second = client.send_with_receipt(send_frame("two")).await.unwrap();
+        assert_ne!(first.id(), second.id());
+        let frame = next_frame(&mut server).await;
+        assert_eq!(
+            wire(frame.clone()),
+            format!("SEND\ndestination:/q\nreceipt:{}\n\none\x00", first.id())
+        );
+        next_frame(&mut server).await;
+
+        // Only the first frame is confirmed
+        server.send(receipt(&frame)).await.unwrap();
+        first.await.unwrap();
+        assert!(matches!(
+            second.timeout(Duration::from_millis(20)).await,
+            Err(StompError::Timeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn receipts_arriving_after_disconnect_are_confirmed() {
+        let (client, mut server) = pair().await;
+        let mut sub = client.subscribe("/a").await.unwrap();
+        let id = expect_subscribe(&mut server).await;
+        let pending = client.send_with_receipt(send_frame("one")).await.unwrap();
+        let sent = next_frame(&mut server).await;
+
+        let disconnect = tokio::spawn({
+            let client = client.clone();
+            async move { client.disconnect().await }
+        });
+        let receipt_id = match next_frame(&mut server).await.content {
+            ToServer::Disconnect {
+                receipt: Some(receipt_id),
+            } => receipt_id,
+            other => panic!("Unexpected frame: {:?}", other),
+        };
+        // The server answers everything sent before the DISCONNECT first
+        server.send(message(&id, "late")).await.unwrap();
+        server.send(receipt(&sent)).await.unwrap();
+        server
+            .send(FromServer::Receipt { receipt_id }.into())
+            .await
+            .unwrap();
+
+        disconnect.await.unwrap().unwrap();
+        pending.await.unwrap();
+        assert_eq!(body(sub.next().await), "late");
+        assert!(sub.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn error_with_receipt_id_fails_the_receipt() {
+        let (client, mut server) = pair().await;
+        let pending = client.send_with_receipt(send_frame("one")).await.unwrap();
+        next_frame(&mut server).await;
+        let error = Message {
+            content: FromServer::Error {
+                message: Some("no such queue".into()),
+                body: None,
+            },
+            extra_headers: vec![(b"receipt-id".to_vec(), pending.id().as_bytes().to_vec())],
+        };
+        server.send(error).await.unwrap();
+        match pending.await {
+            Err(StompError::ServerError(msg)) => assert!(matches!(
+                msg.content,
+                FromServer::Error { message: Some(m), .. } if m == "no such queue"
+            )),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    fn reply(subscription: &str, correlation_id: &str, body: &str) -> Message<FromServer> {
+        let mut msg = message(subscription, body);
+        if let FromServer::Message { headers, .. } = &mut msg.content {
+            headers.push(("correlation-id".into(), correlation_id.into()));
+        }
+        msg
+    }
+
+    /// The reply destination and correlation id of a request, checking the
+    /// rest of the frame
+    fn reply_headers(msg: Message<ToServer>) -> (String, String) {
+        assert!(matches!(
+            &msg.content,
+            ToServer::Send { destination, .. } if destination == "/rpc"
+        ));
+        let header = |key: &[u8]| {
+            let (_, value) = msg.extra_headers.iter().find(|(k, _)| k == key).unwrap();
+            String::from_utf8(value.clone()).unwrap()
+        };
+        (header(b"reply-to"), header(b"correlation-id"))
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_the_reply_subscription() {
+        let (client, mut server) = pair().await;
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.request("/rpc", "one", Duration::from_secs(1)).await }
+        });
+        // The broker sets up the temporary queue by itself
+        let (reply_to, first_id) = reply_headers(next_frame(&mut server).await);
+        assert!(reply_to.starts_with("/temp-queue/"), "{}", reply_to);
+        let second = tokio::spawn({
+            let client = client.clone();
+            async move { client.request("/rpc", "two", Duration::from_secs(1)).await }
+        });
+        let (second_reply_to, second_id) = reply_headers(next_frame(&mut server).await);
+        assert_eq!(reply_to, second_reply_to);
+        assert_ne!(first_id, second_id);
+        assert_eq!(first_id.len(), 32);
+
+        // Replies arrive out of order on the broker's own subscription, along
+        // with one nobody is waiting for
+        let subscription = "/temp-queue/ID:broker-1";
+        server
+            .send(reply(subscription, &second_id, "2"))
+            .await
+            .unwrap();
+        server
+            .send(reply(subscription, "unknown", "?"))
+            .await
+            .unwrap();
+        server
+            .send(reply(subscription, &first_id, "1"))
+            .await
+            .unwrap();
+        let body = |res: Result<Message<FromServer>>| match res.unwrap().content {
+            FromServer::Message { body, .. } => body.unwrap(),
+            other => panic!("Unexpected frame: {:?}", other),
+        };
+        assert_eq!(body(first.await.unwrap()), "1");
+        assert_eq!(body(second.await.unwrap()), "2");
+
+        let late = client.request("/rpc", "three", Duration::from_millis(20));
+        assert!(matches!(late.await, Err(StompError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn reply_destinations_are_unique_to_the_client() {
+        let (first, mut first_server) = pair().await;
+        let (second, mut second_server) = pair().await;
+        let timeout = Duration::from_millis(1);
+        let _ = first.request("/rpc", "one", timeout).await;
+        let _ = second.request("/rpc", "two", timeout).await;
+        let (first_reply_to, _) = reply_headers(next_frame(&mut first_server).await);
+        let (second_reply_to, _) = reply_headers(next_frame(&mut second_server).await);
+        assert_ne!(first_reply_to, second_reply_to);
+    }
+
+    #[tokio::test]
+    async fn other_reply_destinations_are_subscribed_to_once() {
+        let (client, mut server) = pair().await;
+        let client = client.reply_to("/queue/replies");
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.request("/rpc", "one", Duration::from_secs(1)).await }
+        });
+        let subscription = match next_frame(&mut server).await.content {
+            ToServer::Subscribe {
+                destination, id, ..
+            } => {
+                assert_eq!(destination, "/queue/replies");
+                id
+            }
+            other => panic!("Unexpected frame: {:?}", other),
+        };
+        let (reply_to, first_id) = reply_headers(next_frame(&mut server).await);
+        assert_eq!(reply_to, "/queue/replies");
+        let second = tokio::spawn({
+            let client = client.clone();
+            async move { client.request("/rpc", "two", Duration::from_secs(1)).await }
+        });
+        let (_, second_id) = reply_headers(next_frame(&mut server).await);
+
+        for (id, body) in [(&first_id, "1"), (&second_id, "2")] {
+            server.send(reply(&subscription, id, body)).await.unwrap();
+        }
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reply_goes_to_reply_to_with_correlation_id() {
+        let (client, mut server) = pair().await;
+        let mut sub = client.subscribe("/rpc").await.unwrap();
+        next_frame(&mut server).await;
+        let mut request = message("sub-0", "ping");
+        if let FromServer::Message { headers, .. } = &mut request.content {
+            headers.push(("reply-to".into(), "/temp-queue/x".into()));
+            headers.push(("correlation-id".into(), "c-1".into()));
+        }
+        server.send(request).await.unwrap();
+        let request = sub.next().await.unwrap().unwrap();
+        client.reply(&request, "pong").await.unwrap();
+        assert_eq!(
+            wire(next_frame(&mut server).await),
+            "SEND\ndestination:/temp-queue/x\ncorrelation-id:c-1\n\npong\x00"
+        );
+
+        let unaddressed = message("sub-0", "no reply-to");
+        assert!(matches!(
+            client.reply(&unaddressed, "pong").await,
+            Err(StompError::MissingHeader(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn dropped_transaction_is_aborted() {
+        let (client, mut server) = pair().await;
+        let tx = client.begin().await.unwrap();
+        next_frame(&mut server).await;
+        drop(tx);
+        assert_eq!(
+            wire(next_frame(&mut server).await),
+            "ABORT\ntransaction:tx-0\n\n\x00"
+        );
+
+        let tx = client.begin().await.unwrap();
+        next_frame(&mut server).await;
+        let abort = tokio::spawn(tx.abort());
+        let frame = next_frame(&mut server).await;
+        assert!(matches!(frame.content, ToServer::Abort { .. }));
+        // The connection is lost before the receipt arrives
+        drop(server);
+        assert!(matches!(
+            abort.await.unwrap(),
+            Err(StompError::ConnectionClosed)
+        ));
+    }
+
+    fn quick_retries() -> ReconnectPolicy {
+        ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(10))
+            .jitter(0.0)
+            .max_attempts(1)
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_resubscribes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = ConnectOptions::new(listener.local_addr().unwrap().to_string());
+        let (client, mut server) = future::join(
+            Client::connect_reconnecting(options, quick_retries()),
+            accept(&listener),
+        )
+        .await;
+        let client = client.unwrap();
+        let mut events = client.events();
+        let mut sub = client.subscribe("/a").await.unwrap();
+        let id = expect_subscribe(&mut server).await;
+
+        // The broker goes away, and comes back on the same address
+        drop(server);
+        let mut server = accept(&listener).await;
+        assert_eq!(expect_subscribe(&mut server).await, id);
+        server.send(message(&id, "again")).await.unwrap();
+        assert_eq!(body(sub.next().await), "again");
+
+        assert!(matches!(
+            events.next().await,
+            Some(ConnectionEvent::Lost(_))
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(ConnectionEvent::Reconnecting { attempt: 1, .. })
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(ConnectionEvent::Reconnected { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = ConnectOptions::new(listener.local_addr().unwrap().to_string());
+        let (client, mut server) = future::join(
+            Client::connect_reconnecting(options, quick_retries()),
+            accept(&listener),
+        )
+        .await;
+        let client = client.unwrap();
+        let events = client.events();
+        let mut sub = client.subscribe("/a").await.unwrap();
+        expect_subscribe(&mut server).await;
+
+        drop(listener);
+        drop(server);
+        assert!(matches!(
+            sub.next().await,
+            Some(Err(StompError::ConnectionClosed))
+        ));
+        assert!(sub.next().await.is_none());
+        let events: Vec<ConnectionEvent> = events.collect().await;
+        assert!(matches!(
+            events[..],
+            [
+                ConnectionEvent::Lost(_),
+                ConnectionEvent::Reconnecting { .. },
+                ConnectionEvent::AttemptFailed(_),
+                ConnectionEvent::GaveUp
+            ]
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use tokio::net::TcpStream;
+
+use crate::{Result, StompError};
+
+/// How long to wait for one address before also trying the next one,
+/// when racing connection attempts as recommended by RFC 8305
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+type Attempt = (SocketAddr, io::Result<TcpStream>);
+
+/// Resolve `address` without blocking the runtime, then connect to the
+/// resolved addresses in turn until one succeeds.
+/// `timeout` applies to each connection attempt separately.
+pub(crate) async fn connect(
+    address: &str,
+    timeout: Option<Duration>,
+    happy_eyeballs: bool,
+) -> Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(address)
+        .await
+        .map_err(|source| StompError::Resolve {
+            address: address.into(),
+            source,
+        })?
+        .collect();
+    if addrs.is_empty() {
+        return Err(StompError::Resolve {
+            address: address.into(),
+            source: io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+        });
+    }
+    let res = if happy_eyeballs {
+        connect_racing(&addrs, timeout).await
+    } else {
+        connect_in_order(&addrs, timeout).await
+    };
+    res.map_err(|attempts| StompError::Connect {
+        address: address.into(),
+        attempts,
+    })
+}
+
+async fn attempt(addr: SocketAddr, timeout: Option<Duration>) -> Attempt {
+    let res = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+        },
+        None => TcpStream::connect(addr).await,
+    };
+    (addr, res)
+}
+
+async fn connect_in_order(
+    addrs: &[SocketAddr],
+    timeout: Option<Duration>,
+) -> std::result::Result<TcpStream, Vec<(SocketAddr, io::Error)>> {
+    let mut errors = vec![];
+    for &addr in addrs {
+        match attempt(addr, timeout).await {
+            (_, Ok(tcp)) => return Ok(tcp),
+            (addr, Err(e)) => errors.push((addr, e)),
+        }
+    }
+    Err(errors)
+}
+
+/// Happy Eyeballs: alternate between address families and start the next
+/// attempt when the previous one fails or takes longer than `ATTEMPT_DELAY`,
+/// keeping earlier attempts running. The first to connect wins.
+async fn connect_racing(
+    addrs: &[SocketAddr],
+    timeout: Option<Duration>,
+) -> std::result::Result<TcpStream, Vec<(SocketAddr, io::Error)>> {
+    let mut queue = interleave_families(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut errors = vec![];
+    loop {
+        if let Some(addr) = queue.next() {
+            attempts.push(attempt(addr, timeout));
+        }
+        let finished = if queue.peek().is_some() {
+            let delay = tokio::time::sleep(ATTEMPT_DELAY);
+            match future::select(attempts.next(), Box::pin(delay)).await {
+                Either::Left((finished, _)) => finished,
+                Either::Right(_) => continue,
+            }
+        } else {
+            attempts.next().await
+        };
+        match finished {
+            Some((_, Ok(tcp))) => return Ok(tcp),
+            Some((addr, Err(e))) => errors.push((addr, e)),
+            None => return Err(errors),
+        }
+    }
+}
+
+/// Reorder addresses so that IPv6 and IPv4 alternate, starting with the
+/// family of the first address and otherwise keeping the resolver's order
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map(SocketAddr::is_ipv6).unwrap_or(false);
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut res = Vec::with_capacity(addrs.len());
+    first.reverse();
+    second.reverse();
+    while !first.is_empty() || !second.is_empty() {
+        res.extend(first.pop());
+        res.extend(second.pop());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// An address on which nothing is listening
+    async fn closed_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn families_are_interleaved() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:1".parse().unwrap(),
+            "[::2]:1".parse().unwrap(),
+            "10.0.0.1:1".parse().unwrap(),
+            "10.0.0.2:1".parse().unwrap(),
+            "10.0.0.3:1".parse().unwrap(),
+        ];
+        let expect: Vec<SocketAddr> = vec![
+            "[::1]:1".parse().unwrap(),
+            "10.0.0.1:1".parse().unwrap(),
+            "[::2]:1".parse().unwrap(),
+            "10.0.0.2:1".parse().unwrap(),
+            "10.0.0.3:1".parse().unwrap(),
+        ];
+        assert_eq!(interleave_families(&addrs), expect);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_later_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = vec![closed_addr().await, listener.local_addr().unwrap()];
+        let tcp = connect_in_order(&addrs, None).await.unwrap();
+        assert_eq!(tcp.peer_addr().unwrap(), addrs[1]);
+        let tcp = connect_racing(&addrs, None).await.unwrap();
+        assert_eq!(tcp.peer_addr().unwrap(), addrs[1]);
+    }
+
+    #[tokio::test]
+    async fn error_lists_every_address() {
+        let addrs = vec![closed_addr().await, closed_addr().await];
+        let errors = connect_racing(&addrs, None).await.err().unwrap();
+        let tried: Vec<SocketAddr> = errors.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(tried, addrs);
+
+        let address = addrs[0].to_string();
+        match connect(&address, None, false).await.err().unwrap() {
+            StompError::Connect { attempts, .. } => assert_eq!(attempts[0].0, addrs[0]),
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn bad_address_is_an_error() {
+        assert!(connect("no-port-here", None, false).await.is_err());
+    }
+}
@@ -0,0 +1,31 @@
+//! Fixtures shared by the tests of several modules
+
+use futures::prelude::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::server::ServerCodec;
+use crate::{FromServer, Message, ToServer};
+
+/// Play the server's part of the handshake on `stream`: read the CONNECT
+/// frame and answer with CONNECTED, offering `heartbeat`. Returns the framed
+/// connection and the CONNECT frame.
+pub(crate) async fn handshake<S>(
+    stream: S,
+    heartbeat: Option<(u32, u32)>,
+) -> (Framed<S, ServerCodec>, Message<ToServer>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut server = ServerCodec::new().framed(stream);
+    let connect = server.next().await.unwrap().unwrap();
+    assert!(matches!(connect.content, ToServer::Connect { .. }));
+    let connected = FromServer::Connected {
+        version: "1.2".into(),
+        session: None,
+        server: None,
+        heartbeat,
+    };
+    server.send(connected.into()).await.unwrap();
+    (server, connect)
+}
@@ -0,0 +1,238 @@
+//! STOMP over TLS (`stomp+ssl`), using rustls. Requires the `tls` feature.
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use crate::client::{ClientTransport, ConnectOptions};
+use crate::{Result, StompError};
+
+pub use tokio_rustls::rustls::pki_types;
+
+/// A connection to a STOMP server over TLS
+pub type TlsTransport = ClientTransport<TlsStream<TcpStream>>;
+
+/// TLS settings used by [`connect_tls`]
+#[derive(Debug)]
+pub struct TlsOptions {
+    roots: RootCertStore,
+    server_name: Option<String>,
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl TlsOptions {
+    /// TLS settings which trust no certificates until roots are added
+    pub fn new() -> TlsOptions {
+        TlsOptions {
+            roots: RootCertStore::empty(),
+            server_name: None,
+            client_auth: None,
+            alpn: vec![],
+        }
+    }
+
+    /// Trust the given root certificate
+    pub fn add_root_certificate(mut self, cert: CertificateDer<'static>) -> Result<TlsOptions> {
+        self.roots.add(cert)?;
+        Ok(self)
+    }
+
+    /// Replace the trusted root certificates
+    pub fn root_certificates(mut self, roots: RootCertStore) -> TlsOptions {
+        self.roots = roots;
+        self
+    }
+
+    /// The name used for SNI and for checking the server's certificate.
+    /// Defaults to the host name part of the connection address.
+    pub fn server_name(mut self, name: impl Into<String>) -> TlsOptions {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// Present a client certificate chain, for mutual TLS
+    pub fn client_certificate(
+        mut self,
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> TlsOptions {
+        self.client_auth = Some((chain, key));
+        self
+    }
+
+    /// The protocols to offer via ALPN, most preferred first
+    pub fn alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> TlsOptions {
+        self.alpn = protocols;
+        self
+    }
+
+    fn client_config(&self) -> Result<ClientConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots.clone());
+        let mut config = match &self.client_auth {
+            Some((chain, key)) => builder.with_client_auth_cert(chain.clone(), key.clone_key())?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn.clone();
+        Ok(config)
+    }
+}
+
+impl Default for TlsOptions {
+    fn default() -> TlsOptions {
+        TlsOptions::new()
+    }
+}
+
+/// Connect to a STOMP server over TLS, including the connection handshake
+pub async fn connect_tls(options: &ConnectOptions, tls: &TlsOptions) -> Result<TlsTransport> {
+    let connector = TlsConnector::from(Arc::new(tls.client_config()?));
+    let name = tls
+        .server_name
+        .clone()
+        .unwrap_or_else(|| options.host_name().into());
+    let name = ServerName::try_from(name)
+        .map_err(|e| StompError::Tls(rustls::Error::General(e.to_string())))?;
+    let tcp = options.connect_tcp().await?;
+    let stream = connector.connect(name, tcp).await.map_err(tls_error)?;
+    options.handshake(stream).await
+}
+
+/// Failed TLS handshakes are reported as I/O errors wrapping the rustls error
+fn tls_error(e: io::Error) -> StompError {
+    match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(e) => StompError::Tls(e.clone()),
+        None => StompError::Io(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::handshake;
+    use crate::ToServer;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    struct Identity {
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    fn identity(name: &str) -> Identity {
+        let generated = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+        Identity {
+            cert: generated.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der()).into(),
+        }
+    }
+
+    /// Serve a single STOMP handshake over TLS, returning the CONNECT frame
+    /// and the negotiated ALPN protocol
+    async fn serve(listener: TcpListener, config: ServerConfig) -> (ToServer, Option<Vec<u8>>) {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let tls = TlsAcceptor::from(Arc::new(config))
+            .accept(tcp)
+            .await
+            .unwrap();
+        let alpn = tls.get_ref().1.alpn_protocol().map(|p| p.to_vec());
+        let (_server, connect) = handshake(tls, None).await;
+        (connect.content, alpn)
+    }
+
+    fn server_config() -> rustls::ConfigBuilder<ServerConfig, rustls::WantsVerifier> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_with_sni_and_alpn() {
+        let server_id = identity("broker.test");
+        let mut config = server_config()
+            .with_no_client_auth()
+            .with_single_cert(vec![server_id.cert.clone()], server_id.key)
+            .unwrap();
+        config.alpn_protocols = vec![b"stomp".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(serve(listener, config));
+
+        let tls = TlsOptions::new()
+            .add_root_certificate(server_id.cert)
+            .unwrap()
+            .server_name("broker.test")
+            .alpn_protocols(vec![b"stomp".to_vec()]);
+        let options = ConnectOptions::new(address).host("/");
+        connect_tls(&options, &tls).await.unwrap();
+        let (connect, alpn) = server.await.unwrap();
+        assert!(matches!(connect, ToServer::Connect { .. }));
+        assert_eq!(alpn.as_deref(), Some(&b"stomp"[..]));
+    }
+
+    #[tokio::test]
+    async fn presents_client_certificate() {
+        let server_id = identity("localhost");
+        let client_id = identity("client");
+        let mut client_roots = RootCertStore::empty();
+        client_roots.add(client_id.cert.clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(client_roots), provider)
+                .build()
+                .unwrap();
+        let config = server_config()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(vec![server_id.cert.clone()], server_id.key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve(listener, config));
+
+        let tls = TlsOptions::new()
+            .add_root_certificate(server_id.cert)
+            .unwrap()
+            .client_certificate(vec![client_id.cert], client_id.key);
+        let options = ConnectOptions::new(format!("localhost:{}", port));
+        connect_tls(&options, &tls).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate_is_a_tls_error() {
+        let server_id = identity("localhost");
+        let config = server_config()
+            .with_no_client_auth()
+            .with_single_cert(vec![server_id.cert], server_id.key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let _ = TlsAcceptor::from(Arc::new(config)).accept(tcp).await;
+        });
+
+        let tls = TlsOptions::new()
+            .add_root_certificate(identity("other").cert)
+            .unwrap();
+        let options = ConnectOptions::new(format!("localhost:{}", port));
+        match connect_tls(&options, &tls).await {
+            Err(StompError::Tls(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Certificate should not be trusted"),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! STOMP over WebSocket, using tokio-tungstenite. Requires the `websocket` feature.
+//!
+//! Each WebSocket message carries one or more STOMP frames, and a frame may be
+//! split across several messages, so the messages are treated as a byte stream
+//! and framed with [`ClientCodec`](crate::client::ClientCodec) as usual.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures::prelude::*;
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message as WsMessage};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::client::{ClientTransport, ConnectOptions};
+use crate::{Result, StompError};
+
+/// The WebSocket subprotocol for STOMP 1.2
+pub const SUBPROTOCOL: &str = "v12.stomp";
+
+/// A connection to a STOMP server over WebSocket
+pub type WsTransport = ClientTransport<WsStream<TcpStream>>;
+
+/// Presents a WebSocket connection as a byte stream.
+///
+/// The payloads of received text and binary messages are read back to back.
+/// Bytes written are sent as a single message on each flush, as a text message
+/// if they are valid UTF-8 and as a binary message otherwise.
+/// Pings are answered by tungstenite while reading.
+#[derive(Debug)]
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Bytes,
+    write_buf: Vec<u8>,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> WsStream<S> {
+        WsStream {
+            inner,
+            read_buf: Bytes::new(),
+            write_buf: vec![],
+        }
+    }
+
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.inner
+    }
+}
+
+fn ws_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::ErrorKind::BrokenPipe.into()
+        }
+        e => io::Error::other(e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            self.read_buf = match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(Ok(WsMessage::Text(text))) => text.into(),
+                Some(Ok(WsMessage::Binary(data))) => data,
+                Some(Ok(WsMessage::Ping(_))) | Some(Ok(WsMessage::Pong(_))) => continue,
+                Some(Ok(WsMessage::Frame(_))) => continue,
+                Some(Ok(WsMessage::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(ws_io_error(e))),
+            };
+        }
+        let n = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..n]);
+        self.read_buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_buf.is_empty() {
+            ready!(self.inner.poll_ready_unpin(cx)).map_err(ws_io_error)?;
+            let data = std::mem::take(&mut self.write_buf);
+            let msg = match String::from_utf8(data) {
+                Ok(text) => WsMessage::text(text),
+                Err(e) => WsMessage::binary(e.into_bytes()),
+            };
+            self.inner.start_send_unpin(msg).map_err(ws_io_error)?;
+        }
+        self.inner.poll_flush_unpin(cx).map_err(ws_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        match ready!(self.inner.poll_close_unpin(cx)) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(ws_io_error(e))),
+        }
+    }
+}
+
+/// Connect to a STOMP server over WebSocket, including the connection handshake.
+/// `path` is the WebSocket endpoint on the server at `options`' address, e.g. `/ws`.
+pub async fn connect_ws(options: &ConnectOptions, path: &str) -> Result<WsTransport> {
+    let mut request = format!("ws://{}{}", options.address(), path)
+        .into_client_request()
+        .map_err(ws_error)?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(SUBPROTOCOL),
+    );
+    let tcp = options.connect_tcp().await?;
+    let (ws, _) = tokio_tungstenite::client_async(request, tcp)
+        .await
+        .map_err(ws_error)?;
+    options.handshake(WsStream::new(ws)).await
+}
+
+fn ws_error(e: tungstenite::Error) -> StompError {
+    StompError::WebSocket(Box::new(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FromServer, ToServer};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+
+    /// Accept the STOMP subprotocol on the `/ws` endpoint
+    #[allow(clippy::result_large_err)]
+    fn select_protocol(
+        req: &Request,
+        mut resp: Response,
+    ) -> std::result::Result<Response, ErrorResponse> {
+        assert_eq!(req.uri().path(), "/ws");
+        let protocol = req.headers().get("Sec-WebSocket-Protocol").unwrap();
+        assert_eq!(protocol, SUBPROTOCOL);
+        resp.headers_mut()
+            .insert("Sec-WebSocket-Protocol", protocol.clone());
+        Ok(resp)
+    }
+
+    async fn accept(listener: TcpListener) -> WebSocketStream<TcpStream> {
+        let (tcp, _) = listener.accept().await.unwrap();
+        tokio_tungstenite::accept_hdr_async(tcp, select_protocol)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn frames_split_and_batched_across_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut ws = accept(listener).await;
+            let connect = match ws.next().await.unwrap().unwrap() {
+                WsMessage::Text(text) => text,
+                other => panic!("Unexpected message: {:?}", other),
+            };
+            assert!(connect.starts_with("CONNECT\n"));
+            ws.send(WsMessage::text("CONNECTED\nver")).await.unwrap();
+            ws.send(WsMessage::text("sion:1.2\n\n\x00")).await.unwrap();
+            ws.send(WsMessage::Ping(Bytes::from_static(b"ping")))
+                .await
+                .unwrap();
+            let batch = b"MESSAGE\ndestination:/q\nmessage-id:1\nsubscription:s\n\nhi\x00\n\
+                          MESSAGE\ndestination:/q\nmessage-id:2\nsubscription:s\n\n\xff\x00";
+            ws.send(WsMessage::binary(&batch[..])).await.unwrap();
+            let pong = ws.next().await.unwrap().unwrap();
+            assert_eq!(pong, WsMessage::Pong(Bytes::from_static(b"ping")));
+            match ws.next().await.unwrap().unwrap() {
+                WsMessage::Text(text) => assert!(text.starts_with("SUBSCRIBE\n")),
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        });
+
+        let options = ConnectOptions::new(address);
+        let mut conn = connect_ws(&options, "/ws").await.unwrap();
+        for body in [&b"hi"[..], &b"\xff"[..]] {
+            match conn.next().await.unwrap().unwrap().content {
+                FromServer::Message { body: Some(b), .. } => assert_eq!(b, body),
+                other => panic!("Unexpected frame: {:?}", other),
+            }
+        }
+        conn.send(
+            ToServer::Subscribe {
+                destination: "/q".into(),
+                id: "s".into(),
+                ack: None,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_subprotocol_is_a_websocket_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let _ = tokio_tungstenite::accept_async(tcp).await;
+        });
+        match connect_ws(&ConnectOptions::new(address), "/ws").await {
+            Err(StompError::WebSocket(_)) => {}
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Handshake should fail"),
+        }
+    }
+}